@@ -0,0 +1,549 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{
+    condition::Condition,
+    config_model::{Block, Config, Matrix, OPERATING_SYSTEMS, Step, StepsMerge},
+    diagnostic::{ConfigPath, Problem, did_you_mean, suggest},
+    error::RunnerError,
+    interpolate::{check_syntax, find_reference_cycle},
+    parser::{all_steps, block_call, is_identifier, split_words},
+    runner::bind_params,
+    shell::find_executable,
+};
+
+/// Builtins of the usual shells, never mistaken for block calls.
+static SHELL_BUILTINS: &[&str] = &[
+    "alias", "bg", "break", "call", "cd", "chdir", "cls", "continue", "copy", "del", "dir", "echo",
+    "eval", "exec", "exit", "export", "fg", "hash", "local", "popd", "pushd", "pwd", "read",
+    "readonly", "return", "set", "setlocal", "shift", "source", "test", "trap", "type", "ulimit",
+    "umask", "unalias", "unset", "wait",
+];
+
+/// Problems found in a configuration without running any of its steps.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub errors: Vec<Problem>,
+    pub warnings: Vec<String>,
+}
+
+/// Resolves every step of every section and block, reporting block references that do not
+/// resolve, calls with wrong arguments and blocks calling each other in a loop.
+pub fn check_config(config: &Config) -> CheckReport {
+    let mut report = CheckReport::default();
+    let block_names = || config.blocks.keys().map(String::as_str);
+
+    for (parent, path, step) in all_steps(config) {
+        if let Some(msg) = check_step_keys(step, parent) {
+            report.errors.push(Problem::new(path, msg));
+            continue;
+        }
+        if let Step::Parallel(_) = step {
+            continue;
+        }
+        let spec = step.spec();
+        let task = spec.call.as_deref().unwrap_or(spec.run.trim());
+
+        let Some(call) = block_call(config, &spec, task) else {
+            if task.starts_with('@') {
+                report.errors.push(Problem::new(
+                    path,
+                    format!(
+                        "Step '{task}' of '{parent}' is not a valid block call, expected '@block key=value ...'"
+                    ),
+                ));
+            } else if let Some(word) = looks_like_block(config, task) {
+                report.errors.push(
+                    Problem::new(
+                        path,
+                        format!(
+                            "Step '{task}' of '{parent}' refers to '{word}' which is neither a block nor a command"
+                        ),
+                    )
+                    .with_help(did_you_mean(word, block_names())),
+                );
+            }
+            continue;
+        };
+        let Some(block) = config.blocks.get(&call.name) else {
+            let path = if spec.call.is_some() {
+                path.key("call")
+            } else {
+                path
+            };
+            report.errors.push(
+                Problem::new(
+                    path,
+                    format!(
+                        "Step '{task}' of '{parent}' calls unknown block '{}'",
+                        call.name
+                    ),
+                )
+                .with_help(did_you_mean(&call.name, block_names())),
+            );
+            continue;
+        };
+        if let Err(RunnerError::Constraints(msg)) = bind_params(&call.name, block, &call.args) {
+            report.errors.push(Problem::new(
+                path,
+                format!("Step '{task}' of '{parent}': {msg}"),
+            ));
+        }
+    }
+
+    if let Some(cycle) = find_block_cycle(config) {
+        report.errors.push(Problem::new(
+            ConfigPath::default().key("blocks").key(&cycle[0]),
+            format!("Blocks call each other: {}", cycle.join(" -> ")),
+        ));
+    }
+
+    report.errors.extend(interpolation_problems(config));
+    report.errors.extend(matrix_problems(config));
+    report.errors.extend(condition_problems(config));
+    report.warnings.extend(shadowing_warnings(config));
+    report.warnings.extend(retry_warnings(config));
+    report.warnings.extend(steps_merge_warnings(config));
+    report
+}
+
+fn check_value(path: ConfigPath, value: &str, problems: &mut Vec<Problem>) {
+    if let Err(msg) = check_syntax(value) {
+        problems.push(Problem::new(path, msg));
+    }
+}
+
+fn check_layer(path: ConfigPath, vars: &HashMap<String, String>, problems: &mut Vec<Problem>) {
+    let count = problems.len();
+    let mut keys: Vec<&String> = vars.keys().collect();
+    keys.sort();
+    for key in keys {
+        check_value(path.key(key), &vars[key], problems);
+    }
+    if problems.len() == count
+        && let Some(cycle) = find_reference_cycle(vars)
+    {
+        problems.push(Problem::new(
+            path.key(&cycle[0]),
+            format!("Variables reference each other: {}", cycle.join(" -> ")),
+        ));
+    }
+}
+
+/// Reports malformed `${VAR}` references in the values that are interpolated, and variables
+/// of the same `env` referencing each other.
+fn interpolation_problems(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let root = ConfigPath::default();
+
+    if let Some(vars) = config.global_config.as_ref().and_then(|g| g.env.as_ref()) {
+        check_layer(root.key("config").key("env"), vars, &mut problems);
+    }
+    let mut sections: Vec<_> = config.tasks.0.iter().collect();
+    sections.sort_by_key(|(name, _)| *name);
+    for (name, commands) in sections {
+        for os in OPERATING_SYSTEMS {
+            if let Some(vars) = commands
+                .for_os(os)
+                .and_then(|block| block.local_config.as_ref())
+                .and_then(|local| local.env.as_ref())
+            {
+                let path = root.key("tasks").key(name).key(os);
+                check_layer(path.key("config").key("env"), vars, &mut problems);
+            }
+        }
+    }
+    let mut blocks: Vec<_> = config.blocks.iter().collect();
+    blocks.sort_by_key(|(name, _)| *name);
+    for (name, block) in blocks {
+        let path = root.key("blocks").key(name);
+        if let Some(vars) = block.local_config.as_ref().and_then(|l| l.env.as_ref()) {
+            check_layer(path.key("config").key("env"), vars, &mut problems);
+        }
+        let mut params: Vec<_> = block.params.iter().flatten().collect();
+        params.sort_by_key(|(name, _)| *name);
+        for (param, spec) in params {
+            if let Some(default) = &spec.default {
+                check_value(
+                    path.key("params").key(param).key("default"),
+                    default,
+                    &mut problems,
+                );
+            }
+        }
+    }
+
+    for (_, path, step) in all_steps(config) {
+        if let Step::Parallel(_) = step {
+            continue;
+        }
+        let spec = step.spec();
+        if let Some(vars) = &spec.env {
+            check_layer(path.key("env"), vars, &mut problems);
+        }
+        if let Some(cwd) = &spec.cwd {
+            check_value(path.key("cwd"), &cwd.to_string_lossy(), &mut problems);
+        }
+        let task = spec.call.as_deref().unwrap_or(spec.run.trim());
+        if let Some(call) = block_call(config, &spec, task) {
+            let mut args: Vec<_> = call.args.iter().collect();
+            args.sort();
+            for (key, value) in args {
+                let arg_path = match spec.with {
+                    Some(_) => path.key("with").key(key),
+                    None => path.clone(),
+                };
+                check_value(arg_path, value, &mut problems);
+            }
+        }
+    }
+    problems
+}
+
+/// Reports the `if` expressions of sections, blocks and steps that do not parse.
+fn condition_problems(config: &Config) -> Vec<Problem> {
+    let root = ConfigPath::default();
+    let mut conditions = Vec::new();
+    let mut sections: Vec<_> = config.tasks.0.iter().collect();
+    sections.sort_by_key(|(name, _)| *name);
+    for (name, commands) in sections {
+        for os in OPERATING_SYSTEMS {
+            if let Some(condition) = commands.for_os(os).and_then(|b| b.condition.as_ref()) {
+                let path = root.key("tasks").key(name).key(os).key("if");
+                conditions.push((path, name.as_str(), condition.as_str()));
+            }
+        }
+    }
+    let mut blocks: Vec<_> = config.blocks.iter().collect();
+    blocks.sort_by_key(|(name, _)| *name);
+    for (name, block) in blocks {
+        if let Some(condition) = &block.condition {
+            let path = root.key("blocks").key(name).key("if");
+            conditions.push((path, name.as_str(), condition.as_str()));
+        }
+    }
+    for (parent, path, step) in all_steps(config) {
+        if let Step::Detailed(spec) = step
+            && let Some(condition) = &spec.condition
+        {
+            conditions.push((path.key("if"), parent, condition.as_str()));
+        }
+    }
+
+    conditions
+        .into_iter()
+        .filter_map(|(path, owner, condition)| {
+            let e = Condition::parse(condition).err()?;
+            Some(Problem::new(
+                path,
+                format!("Invalid `if` in '{owner}': {e}"),
+            ))
+        })
+        .collect()
+}
+
+/// Reports the matrices with axes that cannot be variables, empty axes, excluded values naming
+/// no axis, or no combination left to run.
+fn matrix_problems(config: &Config) -> Vec<Problem> {
+    fn check(path: ConfigPath, owner: &str, matrix: &Matrix, problems: &mut Vec<Problem>) {
+        let axes = || matrix.axes.iter().map(|(axis, _)| axis.as_str());
+        let mut empty = false;
+        for (axis, values) in &matrix.axes {
+            if !is_identifier(axis) {
+                problems.push(Problem::new(
+                    path.key(axis),
+                    format!("Matrix axis '{axis}' of '{owner}' is not a valid variable name"),
+                ));
+            } else if values.is_empty() {
+                empty = true;
+                problems.push(Problem::new(
+                    path.key(axis),
+                    format!("Matrix axis '{axis}' of '{owner}' has no values"),
+                ));
+            }
+        }
+        for (index, entry) in matrix.exclude.iter().enumerate() {
+            for key in entry.keys().filter(|key| !axes().any(|axis| axis == *key)) {
+                problems.push(
+                    Problem::new(
+                        path.key("exclude").index(index).key(key),
+                        format!(
+                            "Matrix of '{owner}' excludes '{key}' which is not one of its axes"
+                        ),
+                    )
+                    .with_help(did_you_mean(key, axes())),
+                );
+            }
+        }
+        for (index, entry) in matrix.include.iter().enumerate() {
+            for key in entry.keys().filter(|key| !is_identifier(key)) {
+                problems.push(Problem::new(
+                    path.key("include").index(index).key(key),
+                    format!(
+                        "Matrix of '{owner}' includes '{key}' which is not a valid variable name"
+                    ),
+                ));
+            }
+        }
+        if !empty && matrix.combinations().is_empty() {
+            problems.push(Problem::new(
+                path,
+                format!("Matrix of '{owner}' has no combination to run"),
+            ));
+        }
+    }
+
+    let root = ConfigPath::default();
+    let mut problems = Vec::new();
+    let mut sections: Vec<_> = config.tasks.0.iter().collect();
+    sections.sort_by_key(|(name, _)| *name);
+    for (name, commands) in sections {
+        for os in OPERATING_SYSTEMS {
+            if let Some(matrix) = commands.for_os(os).and_then(|block| block.matrix.as_ref()) {
+                let path = root.key("tasks").key(name).key(os).key("matrix");
+                check(path, name, matrix, &mut problems);
+            }
+        }
+    }
+    let mut blocks: Vec<_> = config.blocks.iter().collect();
+    blocks.sort_by_key(|(name, _)| *name);
+    for (name, block) in blocks {
+        if let Some(matrix) = &block.matrix {
+            check(
+                root.key("blocks").key(name).key("matrix"),
+                name,
+                matrix,
+                &mut problems,
+            );
+        }
+    }
+    problems
+}
+
+fn check_step_keys(step: &Step, parent_name: &str) -> Option<String> {
+    let Step::Detailed(spec) = step else {
+        return None;
+    };
+    let has_run = !spec.run.trim().is_empty();
+    if spec.call.is_some() == has_run {
+        Some(format!(
+            "A step of '{parent_name}' needs exactly one of `run` or `call`"
+        ))
+    } else if spec.with.is_some() && spec.call.is_none() {
+        Some(format!(
+            "A step of '{parent_name}' passes `with` arguments without a `call`"
+        ))
+    } else {
+        None
+    }
+}
+
+/// Returns the first word of `task` when it reads like a mistyped block call: a lone name,
+/// possibly followed by `key=value` arguments, that is close to a block name and is neither
+/// a shell builtin nor an executable.
+fn looks_like_block<'t>(config: &Config, task: &'t str) -> Option<&'t str> {
+    if !config.bare_block_calls() || task.lines().count() != 1 {
+        return None;
+    }
+    let word = task.split_whitespace().next()?;
+    let args = split_words(task)?;
+    if !args.iter().skip(1).all(|arg| arg.contains('=')) {
+        return None;
+    }
+    if SHELL_BUILTINS.contains(&word) || word.contains(['/', '\\', '$', '=']) {
+        return None;
+    }
+    suggest(word, config.blocks.keys().map(String::as_str))?;
+    if find_executable(word).is_some() {
+        return None;
+    }
+    Some(word)
+}
+
+/// Returns the blocks called by the steps of `block_name`.
+fn called_blocks<'c>(config: &'c Config, block_name: &str) -> Vec<&'c str> {
+    fn collect<'c>(config: &'c Config, steps: &[Step], out: &mut Vec<&'c str>) {
+        for step in steps {
+            if let Step::Parallel(group) = step {
+                collect(config, &group.parallel, out);
+                continue;
+            }
+            let spec = step.spec();
+            let task = spec.call.as_deref().unwrap_or(spec.run.trim());
+            if let Some(call) = block_call(config, &spec, task)
+                && let Some((name, _)) = config.blocks.get_key_value(&call.name)
+            {
+                out.push(name.as_str());
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    if let Some(block) = config.blocks.get(block_name) {
+        for steps in [&block.steps, &block.on_failure, &block.finally]
+            .into_iter()
+            .flatten()
+        {
+            collect(config, steps, &mut out);
+        }
+    }
+    out
+}
+
+/// Returns the first loop of block calls, as a path starting and ending with the same block.
+fn find_block_cycle(config: &Config) -> Option<Vec<String>> {
+    fn visit<'c>(
+        edges: &HashMap<&'c str, Vec<&'c str>>,
+        name: &'c str,
+        path: &mut Vec<&'c str>,
+        finished: &mut BTreeSet<&'c str>,
+    ) -> Option<Vec<String>> {
+        if let Some(start) = path.iter().position(|n| *n == name) {
+            let mut cycle: Vec<String> = path[start..].iter().map(|n| n.to_string()).collect();
+            cycle.push(name.to_string());
+            return Some(cycle);
+        }
+        if finished.contains(name) {
+            return None;
+        }
+        path.push(name);
+        for callee in edges.get(name).into_iter().flatten() {
+            if let Some(cycle) = visit(edges, callee, path, finished) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        finished.insert(name);
+        None
+    }
+
+    let mut names: Vec<&str> = config.blocks.keys().map(String::as_str).collect();
+    names.sort();
+    let edges: HashMap<&str, Vec<&str>> = names
+        .iter()
+        .map(|name| (*name, called_blocks(config, name)))
+        .collect();
+    let mut finished = BTreeSet::new();
+    names
+        .into_iter()
+        .find_map(|name| visit(&edges, name, &mut Vec::new(), &mut finished))
+}
+
+/// Warns about block names that can be mistaken for commands when called by their bare name.
+fn shadowing_warnings(config: &Config) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if !config.bare_block_calls() {
+        return warnings;
+    }
+    let steps = all_steps(config);
+    let mut names: Vec<&String> = config.blocks.keys().collect();
+    names.sort();
+    for name in names {
+        if let Some(path) = find_executable(name) {
+            warnings.push(format!(
+                "Block '{name}' shadows the executable '{}', steps starting with '{name}' may run the block instead, call it as '@{name}' or set `bare_block_calls: false`",
+                path.display()
+            ));
+        }
+        for (parent, _, step) in &steps {
+            let Step::Command(task) = step else {
+                continue;
+            };
+            let task = task.trim();
+            if task.split_whitespace().next() == Some(name.as_str())
+                && block_call(config, &step.spec(), task).is_none()
+            {
+                warnings.push(format!(
+                    "Step '{task}' of '{parent}' runs as a command although '{name}' is a block, call the block as '@{name}' or rename it"
+                ));
+            }
+        }
+    }
+    warnings
+}
+
+/// Warns about retry settings left without effect by a step or block having no `retries`.
+fn retry_warnings(config: &Config) -> Vec<String> {
+    fn check(
+        path: ConfigPath,
+        retries: Option<u32>,
+        settings: [bool; 3],
+        warnings: &mut Vec<String>,
+    ) {
+        if retries.unwrap_or(0) == 0 && settings.contains(&true) {
+            warnings.push(format!(
+                "`{path}` sets `retry_delay`, `retry_backoff` or `retry_on_exit_codes` without `retries`, they have no effect"
+            ));
+        }
+    }
+    fn block_settings(block: &Block) -> [bool; 3] {
+        [
+            block.retry_delay.is_some(),
+            block.retry_backoff.is_some(),
+            block.retry_on_exit_codes.is_some(),
+        ]
+    }
+
+    let root = ConfigPath::default();
+    let mut warnings = Vec::new();
+    let mut sections: Vec<_> = config.tasks.0.iter().collect();
+    sections.sort_by_key(|(name, _)| *name);
+    for (name, commands) in sections {
+        for os in OPERATING_SYSTEMS {
+            if let Some(block) = commands.for_os(os) {
+                let path = root.key("tasks").key(name).key(os);
+                check(path, block.retries, block_settings(block), &mut warnings);
+            }
+        }
+    }
+    let mut blocks: Vec<_> = config.blocks.iter().collect();
+    blocks.sort_by_key(|(name, _)| *name);
+    for (name, block) in blocks {
+        let path = root.key("blocks").key(name);
+        check(path, block.retries, block_settings(block), &mut warnings);
+    }
+    for (_, path, step) in all_steps(config) {
+        if let Step::Detailed(spec) = step {
+            let settings = [
+                spec.retry_delay.is_some(),
+                spec.retry_backoff.is_some(),
+                spec.retry_on_exit_codes.is_some(),
+            ];
+            check(path, spec.retries, settings, &mut warnings);
+        }
+    }
+    warnings
+}
+
+/// Warns about `steps_merge` on blocks overriding none of the file they extend, the loader
+/// clearing it on the blocks it merged.
+fn steps_merge_warnings(config: &Config) -> Vec<String> {
+    let check = |path: ConfigPath, block: &Block| {
+        matches!(
+            block.steps_merge,
+            Some(StepsMerge::Prepend | StepsMerge::Append)
+        )
+        .then(|| {
+            format!(
+                "`{path}` sets `steps_merge` but overrides no block of an extended file, it has no effect"
+            )
+        })
+    };
+
+    let root = ConfigPath::default();
+    let mut warnings = Vec::new();
+    let mut sections: Vec<_> = config.tasks.0.iter().collect();
+    sections.sort_by_key(|(name, _)| *name);
+    for (name, commands) in sections {
+        for os in OPERATING_SYSTEMS {
+            if let Some(block) = commands.for_os(os) {
+                warnings.extend(check(root.key("tasks").key(name).key(os), block));
+            }
+        }
+    }
+    let mut blocks: Vec<_> = config.blocks.iter().collect();
+    blocks.sort_by_key(|(name, _)| *name);
+    for (name, block) in blocks {
+        warnings.extend(check(root.key("blocks").key(name), block));
+    }
+    warnings
+}
@@ -0,0 +1,457 @@
+use std::{
+    fmt,
+    path::Path,
+    process::Command,
+    sync::{Arc, OnceLock},
+};
+
+use crate::{diagnostic::suggest, environment::Environment};
+
+/// Names an expression may refer to on their own.
+static NAMES: &[&str] = &["os", "branch", "true", "false"];
+
+/// Functions an expression may call, with their number of arguments.
+static FUNCTIONS: &[(&str, usize)] =
+    &[("success", 0), ("failure", 0), ("always", 0), ("exists", 1)];
+
+/// Functions reading the outcome of the earlier sections.
+static STATUS_FUNCTIONS: &[&str] = &["success", "failure", "always"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Text(String),
+    Word(String),
+    Dot,
+    Comma,
+    Open,
+    Close,
+    Equal,
+    NotEqual,
+    And,
+    Or,
+    Not,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Text(text) => write!(f, "string '{text}'"),
+            Token::Word(word) => write!(f, "'{word}'"),
+            Token::Dot => f.write_str("'.'"),
+            Token::Comma => f.write_str("','"),
+            Token::Open => f.write_str("'('"),
+            Token::Close => f.write_str("')'"),
+            Token::Equal => f.write_str("'=='"),
+            Token::NotEqual => f.write_str("'!='"),
+            Token::And => f.write_str("'&&'"),
+            Token::Or => f.write_str("'||'"),
+            Token::Not => f.write_str("'!'"),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Expr {
+    Text(String),
+    Bool(bool),
+    Env(String),
+    Os,
+    Branch,
+    Call(&'static str, Vec<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Equal(Box<Expr>, Box<Expr>),
+    NotEqual(Box<Expr>, Box<Expr>),
+}
+
+enum Value {
+    Text(String),
+    Bool(bool),
+}
+
+impl Value {
+    fn holds(&self) -> bool {
+        match self {
+            Value::Text(text) => !text.is_empty(),
+            Value::Bool(b) => *b,
+        }
+    }
+
+    fn into_text(self) -> String {
+        match self {
+            Value::Text(text) => text,
+            Value::Bool(b) => b.to_string(),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '.' => Token::Dot,
+            ',' => Token::Comma,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '=' | '!' if chars.next_if(|(_, next)| *next == '=').is_some() => match c {
+                '=' => Token::Equal,
+                _ => Token::NotEqual,
+            },
+            '!' => Token::Not,
+            '&' | '|' if chars.next_if(|(_, next)| *next == c).is_some() => match c {
+                '&' => Token::And,
+                _ => Token::Or,
+            },
+            '\'' | '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, q)) if q == c => break,
+                        Some((_, ch)) => text.push(ch),
+                        None => return Err(format!("string '{c}{text}' is not closed")),
+                    }
+                }
+                Token::Text(text)
+            }
+            c if c.is_ascii_alphanumeric() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some((_, ch)) =
+                    chars.next_if(|(_, ch)| ch.is_ascii_alphanumeric() || *ch == '_')
+                {
+                    word.push(ch);
+                }
+                Token::Word(word)
+            }
+            c => return Err(format!("unexpected '{c}'")),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over `||`, `&&`, `!`, comparisons and operands, in increasing order of
+/// precedence.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.peek() == Some(token);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, token: Token, what: &str) -> Result<(), String> {
+        if self.eat(&token) {
+            Ok(())
+        } else {
+            Err(format!("expected {what}"))
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Or) {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.not()?;
+        while self.eat(&Token::And) {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.eat(&Token::Not) {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        let left = self.operand()?;
+        if self.eat(&Token::Equal) {
+            Ok(Expr::Equal(Box::new(left), Box::new(self.operand()?)))
+        } else if self.eat(&Token::NotEqual) {
+            Ok(Expr::NotEqual(Box::new(left), Box::new(self.operand()?)))
+        } else {
+            Ok(left)
+        }
+    }
+
+    fn operand(&mut self) -> Result<Expr, String> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        match token {
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                self.expect(Token::Close, "')'")?;
+                Ok(expr)
+            }
+            Some(Token::Text(text)) => Ok(Expr::Text(text)),
+            Some(Token::Word(word)) if word.starts_with(|c: char| c.is_ascii_digit()) => {
+                Ok(Expr::Text(word))
+            }
+            Some(Token::Word(word)) if self.peek() == Some(&Token::Open) => self.call(&word),
+            Some(Token::Word(word)) => self.name(&word),
+            Some(token) => Err(format!("unexpected {token}")),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+
+    fn name(&mut self, word: &str) -> Result<Expr, String> {
+        match word {
+            "env" => {
+                self.expect(Token::Dot, "'.' after 'env'")?;
+                match self.peek().cloned() {
+                    Some(Token::Word(name)) => {
+                        self.position += 1;
+                        Ok(Expr::Env(name))
+                    }
+                    _ => Err("expected a variable name after 'env.'".to_string()),
+                }
+            }
+            "os" => Ok(Expr::Os),
+            "branch" => Ok(Expr::Branch),
+            "true" => Ok(Expr::Bool(true)),
+            "false" => Ok(Expr::Bool(false)),
+            _ => {
+                let hint = suggest(word, NAMES.iter().copied().chain(["env"]))
+                    .map(|name| format!(" (did you mean '{name}'?)"))
+                    .unwrap_or_default();
+                Err(format!(
+                    "unknown name '{word}'{hint}, strings are quoted and variables read as 'env.NAME'"
+                ))
+            }
+        }
+    }
+
+    fn call(&mut self, word: &str) -> Result<Expr, String> {
+        let Some(&(name, arity)) = FUNCTIONS.iter().find(|(name, _)| *name == word) else {
+            let hint = suggest(word, FUNCTIONS.iter().map(|(name, _)| *name))
+                .map(|name| format!(" (did you mean '{name}'?)"))
+                .unwrap_or_default();
+            return Err(format!("unknown function '{word}()'{hint}"));
+        };
+        self.expect(Token::Open, "'('")?;
+        let mut args = Vec::new();
+        if !self.eat(&Token::Close) {
+            loop {
+                args.push(self.or()?);
+                if self.eat(&Token::Close) {
+                    break;
+                }
+                self.expect(Token::Comma, "',' or ')'")?;
+            }
+        }
+        if args.len() != arity {
+            return Err(format!(
+                "'{name}()' takes {arity} argument(s), {} given",
+                args.len()
+            ));
+        }
+        Ok(Expr::Call(name, args))
+    }
+}
+
+/// An `if:` expression deciding whether a section, block or step runs.
+#[derive(Debug)]
+pub(crate) struct Condition(Expr);
+
+impl Condition {
+    pub(crate) fn parse(source: &str) -> Result<Condition, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            position: 0,
+        };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(Condition(expr)),
+            Some(token) => Err(format!("unexpected {token}")),
+        }
+    }
+
+    /// Whether the expression reads the outcome of the earlier sections, which lets it run
+    /// after a failure.
+    pub(crate) fn checks_status(&self) -> bool {
+        fn visit(expr: &Expr) -> bool {
+            match expr {
+                Expr::Call(name, args) => STATUS_FUNCTIONS.contains(name) || args.iter().any(visit),
+                Expr::Not(inner) => visit(inner),
+                Expr::And(left, right)
+                | Expr::Or(left, right)
+                | Expr::Equal(left, right)
+                | Expr::NotEqual(left, right) => visit(left) || visit(right),
+                _ => false,
+            }
+        }
+        visit(&self.0)
+    }
+
+    pub(crate) fn holds(&self, env: &Environment) -> bool {
+        evaluate(&self.0, env).holds()
+    }
+}
+
+fn evaluate(expr: &Expr, env: &Environment) -> Value {
+    match expr {
+        Expr::Text(text) => Value::Text(text.clone()),
+        Expr::Bool(b) => Value::Bool(*b),
+        Expr::Env(name) => Value::Text(env.get_value(name).unwrap_or_default().to_string()),
+        Expr::Os => Value::Text(env.os.to_string()),
+        Expr::Branch => Value::Text(env.git_branch.get(env.cwd.as_deref()).to_string()),
+        Expr::Call(name, args) => Value::Bool(match *name {
+            "success" => !env.earlier_failure,
+            "failure" => env.earlier_failure,
+            "always" => true,
+            _ => {
+                let path = evaluate(&args[0], env).into_text();
+                match &env.cwd {
+                    Some(cwd) => cwd.join(path).exists(),
+                    None => Path::new(&path).exists(),
+                }
+            }
+        }),
+        Expr::Not(inner) => Value::Bool(!evaluate(inner, env).holds()),
+        Expr::And(left, right) => {
+            Value::Bool(evaluate(left, env).holds() && evaluate(right, env).holds())
+        }
+        Expr::Or(left, right) => {
+            Value::Bool(evaluate(left, env).holds() || evaluate(right, env).holds())
+        }
+        Expr::Equal(left, right) => {
+            Value::Bool(evaluate(left, env).into_text() == evaluate(right, env).into_text())
+        }
+        Expr::NotEqual(left, right) => {
+            Value::Bool(evaluate(left, env).into_text() != evaluate(right, env).into_text())
+        }
+    }
+}
+
+/// Branch the `branch` expressions compare against, looked up the first time one is evaluated
+/// and shared by every clone of an environment, so that a run spawns `git` at most once.
+#[derive(Debug, Default, Clone)]
+pub struct GitBranch(Arc<OnceLock<String>>);
+
+impl GitBranch {
+    fn get(&self, cwd: Option<&Path>) -> &str {
+        self.0.get_or_init(|| current_branch(cwd))
+    }
+}
+
+/// Returns the branch checked out in `cwd`, empty outside of a git repository.
+fn current_branch(cwd: Option<&Path>) -> String {
+    let mut cmd = Command::new("git");
+    cmd.args(["rev-parse", "--abbrev-ref", "HEAD"]);
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    match cmd.output() {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::EnvVariableSource;
+
+    fn holds(source: &str, env: &Environment) -> bool {
+        Condition::parse(source).unwrap().holds(env)
+    }
+
+    #[test]
+    fn tokenizer_splits_operators_strings_and_words() {
+        assert_eq!(
+            tokenize("!env.A_1 != 'x y'&&(os==\"linux\")||42").unwrap(),
+            [
+                Token::Not,
+                Token::Word("env".into()),
+                Token::Dot,
+                Token::Word("A_1".into()),
+                Token::NotEqual,
+                Token::Text("x y".into()),
+                Token::And,
+                Token::Open,
+                Token::Word("os".into()),
+                Token::Equal,
+                Token::Text("linux".into()),
+                Token::Close,
+                Token::Or,
+                Token::Word("42".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_strings_and_stray_characters_are_errors() {
+        assert_eq!(
+            tokenize("os == 'linux").unwrap_err(),
+            "string ''linux' is not closed"
+        );
+        assert!(tokenize("branch == \"main").is_err());
+        assert!(tokenize("a & b").is_err());
+        assert!(tokenize("a = b").is_err());
+        assert!(Condition::parse("os == 'linux").is_err());
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let env = Environment::default();
+        assert!(holds("true || false && false", &env));
+        assert!(holds("false && false || true", &env));
+        assert!(!holds("(true || false) && false", &env));
+    }
+
+    #[test]
+    fn not_applies_to_comparisons_and_binds_tighter_than_and() {
+        let mut env = Environment::default();
+        env.upsert_variable("A".into(), "a".into(), EnvVariableSource::Global);
+        assert!(!holds("!env.A == 'a'", &env));
+        assert!(holds("!env.A == 'b'", &env));
+        assert!(holds("!false && true", &env));
+        assert!(!holds("!true || false", &env));
+        assert!(holds("!!env.A", &env));
+        assert!(holds("!env.UNSET", &env));
+    }
+
+    #[test]
+    fn values_compare_as_strings() {
+        let mut env = Environment::default();
+        env.os = "linux";
+        env.upsert_variable("N".into(), "42".into(), EnvVariableSource::Global);
+        assert!(holds("os == 'linux' && env.N == 42", &env));
+        assert!(holds("true == 'true'", &env));
+        assert!(holds("'text'", &env));
+        assert!(!holds("''", &env));
+    }
+
+    #[test]
+    fn malformed_expressions_are_errors() {
+        for source in [
+            "",
+            "os ==",
+            "(true",
+            "true false",
+            "env",
+            "exists()",
+            "bogus",
+        ] {
+            assert!(Condition::parse(source).is_err(), "{source}");
+        }
+    }
+}
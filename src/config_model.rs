@@ -1,6 +1,16 @@
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{Error as _, MapAccess, Visitor},
+    ser::SerializeMap,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::PathBuf,
+    time::Duration,
+};
 
+/// Sections run, in this order, when the configuration declares no `pipeline`.
 pub(crate) static SECTIONS: &[&str] = &[
     "prebuild",
     "build",
@@ -14,28 +24,27 @@ pub(crate) static SECTIONS: &[&str] = &[
 
 pub(crate) static OPERATING_SYSTEMS: &[&str] = &["windows", "linux", "macos"];
 
-#[derive(Debug, Deserialize, Default)]
-pub struct Tasks {
-    #[serde(rename = "prebuild")]
-    pub prebuild: Option<PlatformCommands>,
-    #[serde(rename = "build")]
-    pub build: Option<PlatformCommands>,
-    #[serde(rename = "postbuild")]
-    pub postbuild: Option<PlatformCommands>,
-    #[serde(rename = "test")]
-    pub test: Option<PlatformCommands>,
-    #[serde(rename = "predeploy")]
-    pub predeploy: Option<PlatformCommands>,
-    #[serde(rename = "deploy")]
-    pub deploy: Option<PlatformCommands>,
-    #[serde(rename = "postdeploy")]
-    pub postdeploy: Option<PlatformCommands>,
-    #[serde(rename = "clean")]
-    pub clean: Option<PlatformCommands>,
-}
-
-#[derive(Debug, Deserialize, Default)]
+/// Sections by name, their order is given by [`Config::pipeline`].
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(transparent)]
+pub struct Tasks(pub HashMap<String, PlatformCommands>);
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Files merged into this configuration, relative to the file including them. Emptied
+    /// once they are merged.
+    #[serde(default, skip_serializing)]
+    pub include: Vec<PathBuf>,
+
+    /// Configuration this one overrides, relative to this file. Unset once it is merged.
+    #[serde(skip_serializing)]
+    pub extends: Option<PathBuf>,
+
+    /// Names of the sections in execution order, [`SECTIONS`] when omitted.
+    pub pipeline: Option<Vec<String>>,
+
+    #[serde(default)]
     pub tasks: Tasks,
 
     #[serde(default)]
@@ -43,9 +52,15 @@ pub struct Config {
 
     #[serde(rename = "config", default)]
     pub global_config: Option<GlobalConfig>,
+
+    /// Steps run once the whole run failed, see [`Block::on_failure`].
+    pub on_failure: Option<Vec<Step>>,
+
+    /// Steps run once the whole run is over, see [`Block::finally`].
+    pub finally: Option<Vec<Step>>,
 }
 
-#[derive(Debug, Deserialize, Default, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Default, Clone, PartialEq, Eq)]
 pub enum ExecutionPolicy {
     #[default]
     #[serde(rename = "fast_fail")]
@@ -54,7 +69,8 @@ pub enum ExecutionPolicy {
     CarryFroward,
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct GlobalConfig {
     #[serde(rename = "execution_policy")]
     pub execution_policy: Option<ExecutionPolicy>,
@@ -62,42 +78,654 @@ pub struct GlobalConfig {
     pub env: Option<HashMap<String, String>>,
     #[serde(rename = "skip_sections")]
     pub banned_sections: Option<Vec<String>>,
+    /// Exit with success even when failures were carried forward.
+    pub carry_forward_silent: Option<bool>,
+    /// Whether a step made of a block name, optionally followed by `key=value` arguments,
+    /// calls the block. `@block` and `call:` always do. Enabled by default.
+    pub bare_block_calls: Option<bool>,
+    /// Default for [`Block::session`].
+    pub session: Option<bool>,
+    pub shell: Option<Shell>,
+    /// Seconds the whole run may take, hooks aside.
+    pub timeout: Option<u64>,
+    /// Seconds a timed out command gets to exit after `SIGTERM`, before `SIGKILL`. 5 by
+    /// default.
+    pub timeout_grace: Option<u64>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct PlatformCommands {
+    /// Sections that have to finish first, when omitted the section waits for all the
+    /// sections before it in the pipeline.
+    pub needs: Option<Vec<String>>,
     pub windows: Option<Block>,
     pub linux: Option<Block>,
     pub macos: Option<Block>,
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct LocalConfig {
     #[serde(rename = "execution_policy")]
     pub execution_policy: Option<ExecutionPolicy>,
     #[serde(rename = "env")]
     pub env: Option<HashMap<String, String>>,
+    pub shell: Option<Shell>,
+}
+
+/// Interpreter running the steps, either a built-in profile or a custom argv template.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Shell {
+    Profile(ShellProfile),
+    Custom(CustomShell),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ShellProfile {
+    Sh,
+    Bash,
+    Zsh,
+    Pwsh,
+    Powershell,
+    Python,
+    Cmd,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct CustomShell {
+    /// Program and arguments, `{script}` is replaced by the step, which is appended as the
+    /// last argument when no placeholder is present.
+    pub argv: Vec<String>,
+    /// How variables set by the step are read back, nothing is captured by default.
+    #[serde(default)]
+    pub capture: CaptureStrategy,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureStrategy {
+    Posix,
+    Pwsh,
+    Python,
+    #[default]
+    None,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Block {
-    pub steps: Option<Vec<String>>,
+    pub steps: Option<Vec<Step>>,
+    /// How `steps` combine with the ones of the block this one overrides through `extends`.
+    pub steps_merge: Option<StepsMerge>,
     #[serde(rename = "config")]
     pub local_config: Option<LocalConfig>,
+    /// Run all the steps in one long lived shell instead of a process per step.
+    pub session: Option<bool>,
+    /// Run the steps concurrently, see [`ParallelGroup`].
+    pub parallel: Option<bool>,
+    pub max_parallel: Option<usize>,
+    pub shared_env: Option<Vec<String>>,
+    /// Arguments taken by the block, visible to its steps as variables during a call.
+    pub params: Option<HashMap<String, BlockParam>>,
+    /// Runs the steps once per combination of values, see [`Matrix`].
+    pub matrix: Option<Matrix>,
+    /// Seconds the block may run for, its hooks aside.
+    pub timeout: Option<u64>,
+    /// Extra runs of the whole block after it failed, see [`Retry`].
+    pub retries: Option<u32>,
+    pub retry_delay: Option<u64>,
+    pub retry_backoff: Option<Backoff>,
+    pub retry_on_exit_codes: Option<Vec<i32>>,
+    /// Expression the block only runs when it holds.
+    #[serde(rename = "if")]
+    pub condition: Option<String>,
+    /// Steps run after `steps` failed, before `finally`.
+    pub on_failure: Option<Vec<Step>>,
+    /// Steps run after `steps` whatever their outcome, Ctrl-C included.
+    pub finally: Option<Vec<Step>>,
+}
+
+/// Sets of values a block runs its steps with, one run per combination. Axes are listed in
+/// declaration order, each combination taking one value of every axis.
+#[derive(Debug, Clone, Default)]
+pub struct Matrix {
+    pub axes: Vec<(String, Vec<String>)>,
+    /// Values added to the matching combinations, or combinations of their own when none
+    /// matches.
+    pub include: Vec<BTreeMap<String, String>>,
+    /// Combinations left out, an entry matching the combinations having all its values.
+    pub exclude: Vec<BTreeMap<String, String>>,
+}
+
+impl<'de> Deserialize<'de> for Matrix {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MatrixVisitor;
+
+        impl<'de> Visitor<'de> for MatrixVisitor {
+            type Value = Matrix;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a mapping of axes to lists of values, with optional `include` and `exclude` lists")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Matrix, A::Error> {
+                let mut matrix = Matrix::default();
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "include" => matrix.include = map.next_value()?,
+                        "exclude" => matrix.exclude = map.next_value()?,
+                        _ => {
+                            let values = map.next_value()?;
+                            matrix.axes.push((key, values));
+                        }
+                    }
+                }
+                Ok(matrix)
+            }
+        }
+
+        deserializer.deserialize_map(MatrixVisitor)
+    }
+}
+
+impl Serialize for Matrix {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for (axis, values) in &self.axes {
+            map.serialize_entry(axis, values)?;
+        }
+        if !self.include.is_empty() {
+            map.serialize_entry("include", &self.include)?;
+        }
+        if !self.exclude.is_empty() {
+            map.serialize_entry("exclude", &self.exclude)?;
+        }
+        map.end()
+    }
+}
+
+impl Matrix {
+    /// Returns the combinations to run, in declaration order: the product of the axes without
+    /// the excluded combinations, then the included ones.
+    pub fn combinations(&self) -> Vec<Vec<(String, String)>> {
+        let mut combinations: Vec<Vec<(String, String)>> = if self.axes.is_empty() {
+            Vec::new()
+        } else {
+            vec![Vec::new()]
+        };
+        for (axis, values) in &self.axes {
+            combinations = combinations
+                .iter()
+                .flat_map(|combination| {
+                    values.iter().map(move |value| {
+                        let mut combination = combination.clone();
+                        combination.push((axis.clone(), value.clone()));
+                        combination
+                    })
+                })
+                .collect();
+        }
+        let has = |combination: &[(String, String)], key: &String, value: &String| {
+            combination.iter().any(|(k, v)| k == key && v == value)
+        };
+        combinations.retain(|combination| {
+            !self.exclude.iter().any(|entry| {
+                entry
+                    .iter()
+                    .all(|(key, value)| has(combination, key, value))
+            })
+        });
+
+        let is_axis = |key: &String| self.axes.iter().any(|(axis, _)| axis == key);
+        for entry in &self.include {
+            let mut matched = false;
+            for combination in &mut combinations {
+                if !entry
+                    .iter()
+                    .filter(|(key, _)| is_axis(key))
+                    .all(|(key, value)| has(combination, key, value))
+                {
+                    continue;
+                }
+                matched = true;
+                for (key, value) in entry.iter().filter(|(key, _)| !is_axis(key)) {
+                    match combination.iter_mut().find(|(k, _)| k == key) {
+                        Some((_, existing)) => *existing = value.clone(),
+                        None => combination.push((key.clone(), value.clone())),
+                    }
+                }
+            }
+            if !matched {
+                let (mut combination, extra): (Vec<_>, Vec<_>) = entry
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .partition(|(key, _)| is_axis(key));
+                combination
+                    .sort_by_key(|(key, _)| self.axes.iter().position(|(axis, _)| axis == key));
+                combination.extend(extra);
+                combinations.push(combination);
+            }
+        }
+        combinations
+    }
+}
+
+/// Names a combination of a [`Matrix`], as `axis=value` pairs.
+pub fn combination_label(combination: &[(String, String)]) -> String {
+    combination
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockParam {
+    /// Value used when the caller does not pass one.
+    pub default: Option<String>,
+    /// Calls not passing the parameter are rejected, unless it has a default.
+    pub required: Option<bool>,
+}
+
+/// Ways of combining the steps of a block with the ones it overrides.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StepsMerge {
+    #[default]
+    Replace,
+    Prepend,
+    Append,
+}
+
+/// Ways the delay between two attempts grows.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backoff {
+    #[default]
+    Fixed,
+    /// The delay doubles after every retry.
+    Exponential,
+}
+
+/// Retry settings of a step or a block. Every attempt starts from the same environment, only
+/// the one of the last attempt is kept.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retry<'c> {
+    pub retries: u32,
+    pub delay: u64,
+    pub backoff: Backoff,
+    pub on_exit_codes: Option<&'c [i32]>,
+}
+
+impl Retry<'_> {
+    /// Delay before the retry number `retry`, the first one being 1.
+    pub fn delay_before(&self, retry: u32) -> Duration {
+        let delay = Duration::from_secs(self.delay);
+        match self.backoff {
+            Backoff::Fixed => delay,
+            Backoff::Exponential => {
+                delay.saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            }
+        }
+    }
+
+    /// Whether a failure with `exit_code` is worth another attempt, a failure without one
+    /// never is once `on_exit_codes` is set.
+    pub fn retries_exit_code(&self, exit_code: Option<i32>) -> bool {
+        match self.on_exit_codes {
+            Some(codes) => exit_code.is_some_and(|code| codes.contains(&code)),
+            None => true,
+        }
+    }
+}
+
+/// A single entry of `steps`, either a bare command line or a mapping with per-step settings.
+#[derive(Debug, Serialize, Clone)]
+#[serde(untagged)]
+pub enum Step {
+    Command(String),
+    Parallel(ParallelGroup),
+    Detailed(Box<StepSpec>),
+}
+
+impl<'de> Deserialize<'de> for Step {
+    /// Tells the kinds of step apart by their shape rather than trying each one in turn, so
+    /// that an error in a step mapping is reported as is and at that step.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_yaml::Value::deserialize(deserializer)?;
+        let step = match value {
+            serde_yaml::Value::String(command) => return Ok(Step::Command(command)),
+            // YAML reads `- true` or `- 42` as a boolean or a number, they are commands too.
+            serde_yaml::Value::Bool(command) => return Ok(Step::Command(command.to_string())),
+            serde_yaml::Value::Number(command) => return Ok(Step::Command(command.to_string())),
+            serde_yaml::Value::Mapping(ref map) if map.contains_key("parallel") => {
+                serde_yaml::from_value(value).map(Step::Parallel)
+            }
+            serde_yaml::Value::Mapping(_) => serde_yaml::from_value(value).map(Step::Detailed),
+            _ => {
+                return Err(D::Error::custom(crate::diagnostic::STEP_EXPECTED));
+            }
+        };
+        step.map_err(D::Error::custom)
+    }
+}
+
+/// Steps run concurrently, their output being prefixed with their name.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ParallelGroup {
+    pub parallel: Vec<Step>,
+    pub name: Option<String>,
+    /// Maximum number of steps running at once, the number of CPUs by default.
+    pub max_parallel: Option<usize>,
+    /// Variables several steps may change, the last step declaring them wins. Any other
+    /// variable changed by more than one step is an error.
+    pub shared_env: Option<Vec<String>>,
+    pub continue_on_error: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct StepSpec {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub run: String,
+    /// Block to call instead of running a command line.
+    pub call: Option<String>,
+    /// Arguments of the called block.
+    pub with: Option<HashMap<String, String>>,
+    pub name: Option<String>,
+    /// Working directory, relative paths are resolved against the inherited one.
+    pub cwd: Option<PathBuf>,
+    /// Variables visible to this step only.
+    pub env: Option<HashMap<String, String>>,
+    /// Timeout in seconds.
+    pub timeout: Option<u64>,
+    /// Extra attempts after the first failure, see [`Retry`].
+    pub retries: Option<u32>,
+    /// Seconds to wait before the first retry.
+    pub retry_delay: Option<u64>,
+    /// How the delay grows from one retry to the next.
+    pub retry_backoff: Option<Backoff>,
+    /// Exit codes worth a retry, any failure is when unset.
+    pub retry_on_exit_codes: Option<Vec<i32>>,
+    pub continue_on_error: Option<bool>,
+    pub shell: Option<Shell>,
+    /// Expression the step only runs when it holds.
+    #[serde(rename = "if")]
+    pub condition: Option<String>,
+}
+
+impl Step {
+    /// Returns the settings of this step, parallel groups have no command of their own.
+    pub fn spec(&self) -> StepSpec {
+        match self {
+            Step::Command(run) => StepSpec {
+                run: run.clone(),
+                ..Default::default()
+            },
+            Step::Parallel(group) => StepSpec {
+                name: group.name.clone(),
+                continue_on_error: group.continue_on_error,
+                ..Default::default()
+            },
+            Step::Detailed(spec) => spec.as_ref().clone(),
+        }
+    }
+
+    /// Name shown in front of the output of this step when it runs in parallel.
+    pub fn label(&self) -> String {
+        let spec = self.spec();
+        spec.name.or(spec.call).unwrap_or_else(|| {
+            spec.run
+                .trim()
+                .lines()
+                .next()
+                .unwrap_or("parallel")
+                .to_string()
+        })
+    }
+}
+
+impl StepSpec {
+    pub fn retry(&self) -> Retry<'_> {
+        Retry {
+            retries: self.retries.unwrap_or(0),
+            delay: self.retry_delay.unwrap_or(0),
+            backoff: self.retry_backoff.unwrap_or_default(),
+            on_exit_codes: self.retry_on_exit_codes.as_deref(),
+        }
+    }
+}
+
+impl Block {
+    pub fn retry(&self) -> Retry<'_> {
+        Retry {
+            retries: self.retries.unwrap_or(0),
+            delay: self.retry_delay.unwrap_or(0),
+            backoff: self.retry_backoff.unwrap_or_default(),
+            on_exit_codes: self.retry_on_exit_codes.as_deref(),
+        }
+    }
+}
+
+impl PlatformCommands {
+    /// Returns the block defined for the given operating system, if any.
+    pub fn for_os(&self, os: &str) -> Option<&Block> {
+        match os {
+            "windows" => self.windows.as_ref(),
+            "linux" => self.linux.as_ref(),
+            "macos" => self.macos.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// Handle of a section of a [`SectionRegistry`], only handed out by the registry itself so
+/// that looking its name up again cannot fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SectionId(usize);
+
+impl SectionId {
+    /// Position of the section in the pipeline.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// The sections declared by a configuration, in pipeline order.
+#[derive(Debug)]
+pub struct SectionRegistry<'c> {
+    names: Vec<&'c str>,
+    ids: HashMap<&'c str, SectionId>,
+}
+
+impl<'c> SectionRegistry<'c> {
+    fn new(names: Vec<&'c str>) -> Self {
+        let mut ids = HashMap::new();
+        for (index, name) in names.iter().enumerate() {
+            ids.entry(*name).or_insert(SectionId(index));
+        }
+        Self { names, ids }
+    }
+
+    pub fn id(&self, name: &str) -> Option<SectionId> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, id: SectionId) -> &'c str {
+        self.names[id.0]
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.ids.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Returns every section in pipeline order, duplicated names included.
+    pub fn names(&self) -> &[&'c str] {
+        &self.names
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = SectionId> + use<> {
+        (0..self.names.len()).map(SectionId)
+    }
 }
 
-impl Tasks {
-    /// Returns the tasks in required execution order.
-    pub fn ordered_sections(&self) -> [(&'static str, Option<&PlatformCommands>); 8] {
-        [
-            ("PreBuild", self.prebuild.as_ref()),
-            ("Build", self.build.as_ref()),
-            ("PostBuild", self.postbuild.as_ref()),
-            ("Test", self.test.as_ref()),
-            ("PreDeploy", self.predeploy.as_ref()),
-            ("Deploy", self.deploy.as_ref()),
-            ("PostDeploy", self.postdeploy.as_ref()),
-            ("Clean", self.clean.as_ref()),
-        ]
+impl Config {
+    /// Returns the declared sections, [`SECTIONS`] when the configuration has no `pipeline`.
+    pub fn sections(&self) -> SectionRegistry<'_> {
+        SectionRegistry::new(match &self.pipeline {
+            Some(pipeline) => pipeline.iter().map(String::as_str).collect(),
+            None => SECTIONS.to_vec(),
+        })
+    }
+
+    /// Whether steps can call blocks by their bare name, see [`GlobalConfig::bare_block_calls`].
+    pub fn bare_block_calls(&self) -> bool {
+        self.global_config
+            .as_ref()
+            .and_then(|global_config| global_config.bare_block_calls)
+            .unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(yaml: &str) -> Result<Step, serde_yaml::Error> {
+        serde_yaml::from_str(yaml)
+    }
+
+    #[test]
+    fn steps_are_command_strings_or_mappings() {
+        assert!(matches!(step("echo hi").unwrap(), Step::Command(run) if run == "echo hi"));
+        assert!(matches!(step("true").unwrap(), Step::Command(run) if run == "true"));
+        assert!(matches!(step("42").unwrap(), Step::Command(run) if run == "42"));
+        assert!(step("~").is_err());
+        assert!(step("[echo hi]").is_err());
+        let spec = step("run: echo hi\nname: greet\ncontinue_on_error: true")
+            .unwrap()
+            .spec();
+        assert_eq!(spec.run, "echo hi");
+        assert_eq!(spec.name.as_deref(), Some("greet"));
+    }
+
+    #[test]
+    fn unknown_step_keys_are_rejected() {
+        assert!(step("run: echo hi\ntimout: 5").is_err());
+        assert!(step("run: echo hi\nbogus: 1").is_err());
+    }
+
+    #[test]
+    fn unknown_parallel_group_keys_are_rejected() {
+        assert!(matches!(
+            step("parallel: [echo a, echo b]\nmax_parallel: 1").unwrap(),
+            Step::Parallel(_)
+        ));
+        assert!(step("parallel: [echo a, echo b]\nretrys: 2").is_err());
+    }
+
+    fn matrix(yaml: &str) -> Matrix {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn labels(matrix: &Matrix) -> Vec<String> {
+        matrix
+            .combinations()
+            .iter()
+            .map(|combination| combination_label(combination))
+            .collect()
+    }
+
+    #[test]
+    fn combinations_follow_the_axes_order() {
+        let matrix = matrix("os: [linux, macos]\nrust: [stable, nightly]\n");
+        assert_eq!(
+            labels(&matrix),
+            [
+                "os=linux, rust=stable",
+                "os=linux, rust=nightly",
+                "os=macos, rust=stable",
+                "os=macos, rust=nightly",
+            ]
+        );
+        assert!(Matrix::default().combinations().is_empty());
+    }
+
+    #[test]
+    fn partial_excludes_drop_every_matching_combination() {
+        let matrix = matrix(
+            "os: [linux, macos]\nrust: [stable, nightly]\nexclude:\n  - rust: nightly\n  - os: macos\n    rust: stable\n",
+        );
+        assert_eq!(labels(&matrix), ["os=linux, rust=stable"]);
+    }
+
+    #[test]
+    fn includes_extend_matches_or_add_their_own_combination() {
+        let matrix = matrix(
+            "os: [linux, macos]\nrust: [stable]\ninclude:\n  - os: linux\n    features: full\n  - rust: beta\n    os: windows\n    extra: 'yes'\n",
+        );
+        assert_eq!(
+            labels(&matrix),
+            [
+                "os=linux, rust=stable, features=full",
+                "os=macos, rust=stable",
+                "os=windows, rust=beta, extra=yes",
+            ]
+        );
+    }
+
+    fn retry(delay: u64, backoff: Backoff) -> Retry<'static> {
+        Retry {
+            retries: 3,
+            delay,
+            backoff,
+            on_exit_codes: None,
+        }
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_the_delay() {
+        let fixed = retry(5, Backoff::Fixed);
+        assert_eq!(fixed.delay_before(1), Duration::from_secs(5));
+        assert_eq!(fixed.delay_before(4), Duration::from_secs(5));
+
+        let exponential = retry(5, Backoff::Exponential);
+        let delays: Vec<u64> = (1..=4)
+            .map(|retry| exponential.delay_before(retry).as_secs())
+            .collect();
+        assert_eq!(delays, [5, 10, 20, 40]);
+    }
+
+    #[test]
+    fn exponential_backoff_saturates_instead_of_overflowing() {
+        let exponential = retry(u64::MAX / 2, Backoff::Exponential);
+        assert_eq!(
+            exponential.delay_before(0),
+            Duration::from_secs(u64::MAX / 2)
+        );
+        assert_eq!(exponential.delay_before(3), Duration::MAX);
+        assert_eq!(exponential.delay_before(u32::MAX), Duration::MAX);
+    }
+
+    #[test]
+    fn exit_code_filter_needs_an_exit_code() {
+        let codes = [75];
+        let filtered = Retry {
+            on_exit_codes: Some(&codes),
+            ..retry(1, Backoff::Fixed)
+        };
+        assert!(filtered.retries_exit_code(Some(75)));
+        assert!(!filtered.retries_exit_code(Some(1)));
+        assert!(!filtered.retries_exit_code(None));
+        assert!(retry(1, Backoff::Fixed).retries_exit_code(None));
     }
 }
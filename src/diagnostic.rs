@@ -0,0 +1,437 @@
+use std::fmt;
+
+use crate::config_model::Step;
+
+/// Step of a [`ConfigPath`], either a mapping key or a sequence index.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// Location of a node in the configuration, such as `tasks.build.linux.steps[0]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ConfigPath(Vec<PathSegment>);
+
+impl ConfigPath {
+    pub fn key(&self, key: &str) -> Self {
+        let mut path = self.clone();
+        path.0.push(PathSegment::Key(key.to_string()));
+        path
+    }
+
+    pub fn index(&self, index: usize) -> Self {
+        let mut path = self.clone();
+        path.0.push(PathSegment::Index(index));
+        path
+    }
+
+    /// Path of the node containing this one, `None` for the root.
+    pub fn parent(&self) -> Option<Self> {
+        let (_, parent) = self.0.split_last()?;
+        Some(Self(parent.to_vec()))
+    }
+
+    pub fn starts_with(&self, prefix: &ConfigPath) -> bool {
+        self.0.starts_with(&prefix.0)
+    }
+
+    /// Replaces the leading `from` of this path by `to`.
+    pub fn rebase(&self, from: &ConfigPath, to: &ConfigPath) -> Self {
+        let mut path = to.clone();
+        path.0.extend_from_slice(&self.0[from.0.len()..]);
+        path
+    }
+}
+
+impl fmt::Display for ConfigPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                PathSegment::Key(key) if i == 0 => write!(f, "{key}")?,
+                PathSegment::Key(key) => write!(f, ".{key}")?,
+                PathSegment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A problem found while validating the configuration, pointing at the node it is about.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub message: String,
+    pub path: ConfigPath,
+    pub help: Option<String>,
+}
+
+impl Problem {
+    pub fn new(path: ConfigPath, message: String) -> Self {
+        Self {
+            message,
+            path,
+            help: None,
+        }
+    }
+
+    pub fn with_help(mut self, help: Option<String>) -> Self {
+        self.help = help;
+        self
+    }
+}
+
+/// Returns the candidate closest to `name`, if one is close enough to be a likely typo.
+pub(crate) fn suggest<'c>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'c str>,
+) -> Option<&'c str> {
+    candidates
+        .into_iter()
+        .map(|candidate| (strsim::jaro_winkler(name, candidate), candidate))
+        .filter(|(score, _)| *score > 0.8)
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, candidate)| candidate)
+}
+
+/// Returns a "did you mean" hint when one of `candidates` is close to `name`.
+pub(crate) fn did_you_mean<'c>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'c str>,
+) -> Option<String> {
+    suggest(name, candidates).map(|candidate| format!("did you mean '{candidate}'?"))
+}
+
+/// A problem of a configuration file rendered with the source line it is about.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    pub file: String,
+    /// One based line and column, with the length of the highlighted span.
+    pub location: Option<(usize, usize, usize)>,
+    pub help: Option<String>,
+    source_line: Option<String>,
+}
+
+impl Diagnostic {
+    /// Locates `problem` in `source`, the content of `file`.
+    pub fn from_problem(problem: Problem, source: &str, file: &str) -> Self {
+        let location = locate(source, &problem.path.0);
+        let message = if problem.path.0.is_empty() {
+            problem.message
+        } else {
+            format!("{}: {}", problem.path, problem.message)
+        };
+        Self::new(message, problem.help, location, source, file)
+    }
+
+    /// Wraps an error of the YAML parser, suggesting the closest name for unknown keys and
+    /// values.
+    pub fn from_yaml_error(error: &serde_yaml::Error, source: &str, file: &str) -> Self {
+        let mut message = error.to_string();
+        if let Some(pos) = message.rfind(" at line ") {
+            message.truncate(pos);
+        }
+        let help = unknown_name_help(&message).or_else(|| {
+            message.contains(STEP_EXPECTED).then(|| {
+                "a step is a command string, a mapping with `run` or `call`, or a `parallel` group"
+                    .to_string()
+            })
+        });
+        // The parser reports errors in a step at the list holding it, point at the step itself.
+        if let Some((path, rest)) = message.split_once(": ")
+            && let Some(step) = failing_step(source, path)
+        {
+            let message = format!("{step}: {rest}");
+            let location = locate(source, &step.0);
+            return Self::new(message, help, location, source, file);
+        }
+        let location = error.location().map(|location| {
+            let line = source.lines().nth(location.line() - 1).unwrap_or_default();
+            (
+                location.line(),
+                location.column(),
+                token_length(line, location.column() - 1),
+            )
+        });
+        Self::new(message, help, location, source, file)
+    }
+
+    fn new(
+        message: String,
+        help: Option<String>,
+        location: Option<(usize, usize, usize)>,
+        source: &str,
+        file: &str,
+    ) -> Self {
+        let source_line = location
+            .and_then(|(line, _, _)| source.lines().nth(line - 1))
+            .map(str::to_string);
+        Self {
+            message,
+            file: file.to_string(),
+            location,
+            help,
+            source_line,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.message)?;
+        match (self.location, &self.source_line) {
+            (Some((line, column, length)), Some(source_line)) => {
+                let gutter = " ".repeat(line.to_string().len());
+                writeln!(f, "{gutter}--> {}:{line}:{column}", self.file)?;
+                writeln!(f, "{gutter} |")?;
+                writeln!(f, "{line} | {}", source_line.replace('\t', " "))?;
+                write!(
+                    f,
+                    "{gutter} | {}{}",
+                    " ".repeat(column - 1),
+                    "^".repeat(length.max(1))
+                )?;
+                if let Some(help) = &self.help {
+                    write!(f, "\n{gutter} = help: {help}")?;
+                }
+            }
+            _ => {
+                write!(f, " --> {}", self.file)?;
+                if let Some(help) = &self.help {
+                    write!(f, "\n  = help: {help}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renders several diagnostics, one after the other.
+pub(crate) fn render(diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(Diagnostic::to_string)
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Suggests a replacement for serde's "unknown field" and "unknown variant" errors, which
+/// list the expected names between backticks.
+fn unknown_name_help(message: &str) -> Option<String> {
+    let rest = message
+        .split_once("unknown field `")
+        .or_else(|| message.split_once("unknown variant `"))?
+        .1;
+    let (name, expected) = rest.split_once('`')?;
+    let candidates: Vec<&str> = expected.split('`').skip(1).step_by(2).collect();
+    did_you_mean(name, candidates)
+}
+
+/// Error of a step neither a command string nor a mapping.
+pub(crate) const STEP_EXPECTED: &str = "expected a command string or a step mapping";
+
+/// Parses a path of the YAML parser, such as `tasks.build.linux.steps[1]`.
+fn parse_path(path: &str) -> ConfigPath {
+    let mut parsed = ConfigPath::default();
+    for part in path.split('.') {
+        let (key, indices) = part.split_once('[').unwrap_or((part, ""));
+        parsed = parsed.key(key);
+        for index in indices.split('[') {
+            if let Some(index) = index.strip_suffix(']').and_then(|i| i.parse().ok()) {
+                parsed = parsed.index(index);
+            }
+        }
+    }
+    parsed
+}
+
+/// Finds the first step of the list at `path` that does not parse, descending into the
+/// `parallel` group holding it if any. `None` when `path` is not a list of steps.
+fn failing_step(source: &str, path: &str) -> Option<ConfigPath> {
+    let root: serde_yaml::Value = serde_yaml::from_str(source).ok()?;
+    let mut path = parse_path(path);
+    let mut node = &root;
+    for segment in &path.0 {
+        node = match segment {
+            PathSegment::Key(key) => node.get(key.as_str())?,
+            PathSegment::Index(index) => node.get(index)?,
+        };
+    }
+    let mut found = false;
+    while let Some(steps) = node.as_sequence() {
+        let Some((index, step)) = steps
+            .iter()
+            .enumerate()
+            .find(|(_, step)| serde_yaml::from_value::<Step>((*step).clone()).is_err())
+        else {
+            break;
+        };
+        found = true;
+        path = path.index(index);
+        match step.get("parallel") {
+            Some(parallel) => {
+                path = path.key("parallel");
+                node = parallel;
+            }
+            None => break,
+        }
+    }
+    if path.0.last() == Some(&PathSegment::Key("parallel".to_string())) {
+        path = path.parent()?;
+    }
+    found.then_some(path)
+}
+
+/// Length of the word starting at `column` (zero based) of `line`, a key stopping at its colon.
+fn token_length(line: &str, column: usize) -> usize {
+    line.chars()
+        .skip(column)
+        .take_while(|c| !c.is_whitespace() && *c != ':')
+        .count()
+}
+
+/// A non blank, non comment line of the source, with its indentation.
+struct SourceLine<'s> {
+    number: usize,
+    indent: usize,
+    text: &'s str,
+}
+
+fn source_lines(source: &str) -> Vec<SourceLine<'_>> {
+    source
+        .lines()
+        .enumerate()
+        .filter_map(|(number, text)| {
+            let content = text.trim_start();
+            (!content.is_empty() && !content.starts_with('#')).then(|| SourceLine {
+                number,
+                indent: text.len() - content.len(),
+                text,
+            })
+        })
+        .collect()
+}
+
+/// Finds the node at `path` in a block style YAML document, returning its one based line and
+/// column and the length of its first token. Falls back to the deepest node found.
+fn locate(source: &str, path: &[PathSegment]) -> Option<(usize, usize, usize)> {
+    let lines = source_lines(source);
+    // Current node, as an index into `lines` and the column its content starts at.
+    let mut node = Some((0, lines.first()?.indent));
+    let mut found = None;
+
+    for segment in path {
+        let Some((start, column)) = node else {
+            break;
+        };
+        let mut position = None;
+        for (i, line) in lines.iter().enumerate().skip(start) {
+            let content_column = if i == start { column } else { line.indent };
+            if i > start && line.indent < column {
+                break;
+            }
+            if content_column != column {
+                continue;
+            }
+            let content = &line.text[column..];
+            let matched = match segment {
+                PathSegment::Key(key) => [key.clone(), format!("\"{key}\""), format!("'{key}'")]
+                    .iter()
+                    .find_map(|quoted| {
+                        content
+                            .strip_prefix(quoted.as_str())
+                            .and_then(|rest| rest.strip_prefix(':'))
+                            .filter(|rest| rest.is_empty() || rest.starts_with([' ', '\t']))
+                            .map(|rest| (column + content.len() - rest.len(), quoted.len()))
+                    }),
+                PathSegment::Index(_) => {
+                    (content == "-" || content.starts_with("- ")).then_some((column + 1, 1))
+                }
+            };
+            let Some((value_column, length)) = matched else {
+                continue;
+            };
+            if let PathSegment::Index(index) = segment {
+                let nth = lines[start..=i]
+                    .iter()
+                    .enumerate()
+                    .filter(|(offset, l)| {
+                        let c = if *offset == 0 { column } else { l.indent };
+                        c == column && l.text[c..].starts_with('-')
+                    })
+                    .count()
+                    - 1;
+                if nth != *index {
+                    continue;
+                }
+            }
+            position = Some((i, column, length, value_column));
+            break;
+        }
+
+        let Some((i, column, length, value_column)) = position else {
+            break;
+        };
+        let line = &lines[i];
+        let value_offset =
+            line.text[value_column..].len() - line.text[value_column..].trim_start().len();
+        let value_start = value_column + value_offset;
+        let inline_value =
+            value_start < line.text.len() && !line.text[value_start..].starts_with('#');
+        found = Some(match segment {
+            PathSegment::Index(_) if inline_value => (
+                line.number + 1,
+                value_start + 1,
+                line.text[value_start..].trim_end().chars().count(),
+            ),
+            _ => (line.number + 1, column + 1, length),
+        });
+        node = if inline_value {
+            Some((i, value_start))
+        } else {
+            lines
+                .get(i + 1)
+                .filter(|next| {
+                    next.indent > column
+                        || (next.indent == column && next.text[column..].starts_with('-'))
+                })
+                .map(|next| (i + 1, next.indent))
+        };
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml_diagnostic(source: &str) -> Diagnostic {
+        let error = serde_yaml::from_str::<crate::config_model::Config>(source).unwrap_err();
+        Diagnostic::from_yaml_error(&error, source, "zbuild.yml")
+    }
+
+    #[test]
+    fn step_errors_point_at_the_failing_step() {
+        let source = "tasks:\n  build:\n    linux:\n      steps:\n        - echo ok\n        - run: echo hi\n          timout: 5\n";
+        let diagnostic = yaml_diagnostic(source);
+        assert!(
+            diagnostic
+                .message
+                .starts_with("tasks.build.linux.steps[1]: unknown field `timout`")
+        );
+        assert_eq!(diagnostic.location.map(|(line, ..)| line), Some(6));
+        assert_eq!(diagnostic.help.as_deref(), Some("did you mean 'timeout'?"));
+    }
+
+    #[test]
+    fn step_errors_descend_into_parallel_groups() {
+        let source = "tasks:\n  build:\n    linux:\n      steps:\n        - parallel:\n            - echo a\n            - [echo b]\n";
+        let diagnostic = yaml_diagnostic(source);
+        assert!(
+            diagnostic
+                .message
+                .starts_with("tasks.build.linux.steps[0].parallel[1]: ")
+        );
+        assert_eq!(diagnostic.location.map(|(line, ..)| line), Some(7));
+    }
+}
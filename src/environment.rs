@@ -1,8 +1,17 @@
-use crate::{config_model::ExecutionPolicy, error::RunnerError, runner::Section};
+use crate::{
+    condition::GitBranch,
+    config_model::{ExecutionPolicy, SectionId, Shell},
+    error::RunnerError,
+    parser::{is_identifier, parse_env_dump},
+    report::FailureLog,
+    shell::{Capture, INTERNAL_VARIABLES, shell_command},
+};
+use serde::Serialize;
 use std::{
     collections::HashMap,
     path::PathBuf,
-    process::{Command, Stdio},
+    process::Stdio,
+    time::{Duration, Instant},
 };
 
 #[derive(Debug, Default, Clone)]
@@ -12,11 +21,59 @@ pub struct Environment<'a> {
     pub cwd: Option<PathBuf>,
     pub execution_policy: ExecutionPolicy,
     pub dry_run: bool,
-    pub banned_sections: Option<Vec<Section>>,
-    pub sections: Option<Vec<Section>>,
+    pub banned_sections: Option<Vec<SectionId>>,
+    /// Sections requested on the command line, all of them but the skipped ones when unset.
+    pub sections: Option<Vec<SectionId>>,
+    /// Do not pull in the sections needed by the requested ones.
+    pub no_deps: bool,
+    /// Maximum number of sections running at the same time.
+    pub jobs: usize,
+    /// Point in time after which running commands are killed.
+    pub deadline: Option<Deadline>,
+    /// Seconds a timed out command gets to exit after `SIGTERM`, before `SIGKILL`.
+    pub timeout_grace: Option<u64>,
+    /// Whether blocks run their steps in a single shell session unless they say otherwise.
+    pub session: bool,
+    /// Shell picked by the configuration, the platform default is used when unset.
+    pub shell: Option<Shell>,
+    /// Set for steps running in parallel, their output lines are prefixed with it.
+    pub output_prefix: Option<String>,
+    /// Set once a section this one waits for has failed, or the run is stopping after a
+    /// failure, for the `success()` and `failure()` conditions.
+    pub earlier_failure: bool,
+    /// Set while hooks run, Ctrl-C does not stop them.
+    pub uninterruptible: bool,
+    pub failures: FailureLog,
+    /// Branch read by `if` expressions, looked up once per run.
+    pub git_branch: GitBranch,
+}
+
+/// Time limit of the commands run with an environment, the one of the step, its blocks, its
+/// section or the whole run that expires first.
+#[derive(Debug, Clone)]
+pub struct Deadline {
+    /// Step, block or section the `timeout` belongs to, `pipeline` for the whole run.
+    pub owner: String,
+    pub started: Instant,
+    pub at: Instant,
 }
 
-#[derive(Default, Debug, PartialEq, Eq, Clone)]
+impl Deadline {
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.at
+    }
+
+    /// Reports that the time of the owner is up.
+    pub fn error(&self) -> RunnerError {
+        RunnerError::TimedOut {
+            owner: self.owner.clone(),
+            elapsed: self.started.elapsed(),
+        }
+    }
+}
+
+#[derive(Default, Debug, PartialEq, Eq, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum EnvVariableSource {
     #[default]
     Default = 1,
@@ -36,12 +93,48 @@ impl EnvVariableSource {
             EnvVariableSource::Script => 5,
         }
     }
+
+    /// Name of the layer, as written in the documentation.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EnvVariableSource::Default => "default",
+            EnvVariableSource::Global => "global",
+            EnvVariableSource::Local => "local",
+            EnvVariableSource::Passed => "passed",
+            EnvVariableSource::Script => "script",
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct EnvVariable {
     pub(crate) source: EnvVariableSource,
     pub(crate) value: String,
+    /// Tombstone left behind by an `unset`, it keeps the removal alive across merges.
+    pub(crate) removed: bool,
+    /// Values this variable was given before the current one, oldest first.
+    pub(crate) history: Vec<EnvChange>,
+}
+
+/// A value offered for a variable by one of the layers, see [`EnvVariable::history`].
+#[derive(Debug, Clone)]
+pub struct EnvChange {
+    pub source: EnvVariableSource,
+    /// `None` for a removal.
+    pub value: Option<String>,
+    /// Whether the value was replaced later on, rather than refused because a layer with a
+    /// higher priority had already set the variable.
+    pub overridden: bool,
+}
+
+impl EnvVariable {
+    fn as_change(&self, overridden: bool) -> EnvChange {
+        EnvChange {
+            source: self.source.clone(),
+            value: (!self.removed).then(|| self.value.clone()),
+            overridden,
+        }
+    }
 }
 
 impl<'a> Environment<'a> {
@@ -49,61 +142,135 @@ impl<'a> Environment<'a> {
         &self.variables
     }
 
-    fn insert_variable(
+    /// Returns the value of `key` unless it is undefined or has been removed.
+    pub fn get_value(&self, key: &str) -> Option<&str> {
+        self.variables
+            .get(key)
+            .filter(|v| !v.removed)
+            .map(|v| v.value.as_str())
+    }
+
+    fn upsert_entry(&mut self, key: String, mut entry: EnvVariable) -> Option<EnvVariable> {
+        // Entries merged from another environment already carry their history, only fresh
+        // values are recorded.
+        let fresh = entry.history.is_empty();
+        if let Some(old) = self.variables.get_mut(&key) {
+            let old_priority = old.source.get_priority();
+            let new_priority = entry.source.get_priority();
+            let same_value = entry.value == old.value && entry.removed == old.removed;
+            if new_priority < old_priority || (same_value && new_priority == old_priority) {
+                if fresh && new_priority < old_priority {
+                    old.history.push(entry.as_change(false));
+                }
+                return None;
+            }
+            if fresh {
+                entry.history = old.history.clone();
+                entry.history.push(old.as_change(true));
+            }
+        }
+        self.variables.insert(key, entry)
+    }
+
+    pub fn upsert_variable(
         &mut self,
         key: String,
         value: String,
         source: EnvVariableSource,
     ) -> Option<EnvVariable> {
-        self.variables.insert(key, EnvVariable { source, value })
+        self.upsert_entry(
+            key,
+            EnvVariable {
+                source,
+                value,
+                removed: false,
+                history: Vec::new(),
+            },
+        )
     }
 
-    pub fn upsert_variable(
+    /// Marks `key` as removed, following the same priority rules as [`Self::upsert_variable`].
+    pub fn remove_variable(
         &mut self,
         key: String,
-        value: String,
         source: EnvVariableSource,
     ) -> Option<EnvVariable> {
-        if self.variables.contains_key(&key) {
-            let old_priority = self.variables.get(&key).unwrap().source.get_priority();
-            let new_priority = source.get_priority();
-            if value == self.variables.get(&key).unwrap().value {
-                if new_priority > old_priority {
-                    self.insert_variable(key, value, source);
-                }
-                return None;
-            } else {
-                if new_priority >= old_priority {
-                    return self.insert_variable(key, value, source);
-                } else {
-                    return None;
-                }
+        self.upsert_entry(
+            key,
+            EnvVariable {
+                source,
+                value: String::new(),
+                removed: true,
+                history: Vec::new(),
+            },
+        )
+    }
+
+    /// Resets `key` to the state it has in `other`, removing it if `other` does not define it.
+    pub fn restore_variable(&mut self, key: &str, other: &Environment) {
+        match other.variables.get(key) {
+            Some(variable) => {
+                self.variables.insert(key.to_string(), variable.clone());
+            }
+            None => {
+                self.variables.remove(key);
             }
         }
-        return self.insert_variable(key, value, source);
+    }
+
+    /// Lists the variables whose value differs from `base`, `None` standing for a removal.
+    pub fn changes_since(&self, base: &Environment) -> Vec<(String, Option<String>)> {
+        self.variables
+            .keys()
+            .filter(|key| self.get_value(key) != base.get_value(key))
+            .map(|key| (key.clone(), self.get_value(key).map(str::to_string)))
+            .collect()
+    }
+
+    /// Returns a copy of this environment only keeping the variables that differ from `base`.
+    pub fn changed_since(&self, base: &Environment) -> Environment<'a> {
+        let mut changed = self.clone();
+        changed
+            .variables
+            .retain(|key, _| self.get_value(key) != base.get_value(key));
+        changed
+    }
+
+    /// Returns the shell steps run with.
+    pub fn resolved_shell(&self) -> Shell {
+        self.shell
+            .clone()
+            .unwrap_or_else(|| Shell::default_for(self.os))
+    }
+
+    /// Gives the commands run with this environment `timeout` seconds from now on behalf of
+    /// `owner`, unless an inherited limit expires earlier.
+    pub fn limit_time(&mut self, owner: &str, timeout: u64) {
+        let started = Instant::now();
+        let at = started + Duration::from_secs(timeout);
+        if self
+            .deadline
+            .as_ref()
+            .is_none_or(|deadline| at < deadline.at)
+        {
+            self.deadline = Some(Deadline {
+                owner: owner.to_string(),
+                started,
+                at,
+            });
+        }
     }
 
     pub fn merge_env(&mut self, other: Environment) {
-        for (key, origin_value) in other.variables.iter() {
-            let new_origin = origin_value.source.clone();
-            let new_value = origin_value.value.clone();
-            self.upsert_variable(key.to_string(), new_value, new_origin);
+        for (key, origin_value) in other.variables {
+            self.upsert_entry(key, origin_value);
         }
     }
 
     pub fn capture_default_environment(&mut self) -> Result<(), RunnerError> {
-        let mut cmd = if self.os == "windows" {
-            let mut c = Command::new("cmd");
-            c.arg("/C").arg("set > .env.vars.zbuild");
-            c.env("TERM", "xterm-256color");
-            c.env("ANSICON", "1");
-            c
-        } else {
-            let mut c = Command::new("sh");
-            c.arg("-c").arg("env > .env.vars.zbuild");
-            c.env("TERM", "xterm-256color");
-            c
-        };
+        let mut capture = Capture::new()?;
+        let noop = if self.os == "windows" { "rem" } else { ":" };
+        let mut cmd = shell_command(noop, &Shell::default_for(self.os), self.os, &mut capture)?;
 
         let mut child = cmd
             .stdin(Stdio::null())
@@ -119,25 +286,22 @@ impl<'a> Environment<'a> {
             ));
         }
 
-        let env_vars_path = if let Some(ref dir) = self.cwd {
-            dir.join(".env.vars.zbuild")
-        } else {
-            PathBuf::from(".env.vars.zbuild")
-        };
-
-        if env_vars_path.exists()
-            && let Ok(content) = std::fs::read_to_string(&env_vars_path)
-        {
-            self.load_env(content, EnvVariableSource::Default);
-        }
-
-        if env_vars_path.exists() {
-            let _ = std::fs::remove_file(&env_vars_path);
+        if let Some(content) = capture.read() {
+            self.load_env_dump(&content, EnvVariableSource::Default);
         }
 
         Ok(())
     }
 
+    /// Loads the variables of this process as defaults, without starting a shell.
+    pub fn inherit_process_environment(&mut self) {
+        for (key, value) in std::env::vars_os() {
+            if let (Ok(key), Ok(value)) = (key.into_string(), value.into_string()) {
+                self.upsert_variable(key, value, EnvVariableSource::Default);
+            }
+        }
+    }
+
     pub fn load_env(&mut self, content: String, new_origin: EnvVariableSource) {
         for line in content.lines() {
             let line = line.trim();
@@ -149,4 +313,108 @@ impl<'a> Environment<'a> {
             }
         }
     }
+
+    /// Loads a NUL separated dump as written through a [`Capture`].
+    pub fn load_env_dump(&mut self, content: &str, new_origin: EnvVariableSource) {
+        for (key, new_value) in parse_capture(content) {
+            self.upsert_variable(key, new_value, new_origin.clone());
+        }
+    }
+
+    /// Loads the dump of a finished script, diffing it against the variables the script was
+    /// started with: changed values are recorded and missing ones are removed.
+    pub fn load_script_env(&mut self, content: &str) {
+        let dump = parse_capture(content);
+        if dump.is_empty() {
+            return;
+        }
+
+        // Shells silently drop variables they cannot represent, those were not unset.
+        let unset: Vec<String> = self
+            .variables
+            .iter()
+            .filter(|(key, v)| !v.removed && !dump.contains_key(*key) && is_identifier(key))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in unset {
+            self.remove_variable(key, EnvVariableSource::Script);
+        }
+
+        for (key, new_value) in dump {
+            if self.get_value(&key) != Some(new_value.as_str()) {
+                self.upsert_variable(key, new_value, EnvVariableSource::Script);
+            }
+        }
+    }
+}
+
+fn parse_capture(content: &str) -> HashMap<String, String> {
+    let mut dump = parse_env_dump(content);
+    for key in INTERNAL_VARIABLES {
+        dump.remove(*key);
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_env_dump;
+
+    fn environment(entries: &[(&str, &str)]) -> Environment<'static> {
+        let mut env = Environment::default();
+        for (key, value) in entries {
+            env.upsert_variable(
+                key.to_string(),
+                value.to_string(),
+                EnvVariableSource::Global,
+            );
+        }
+        env
+    }
+
+    #[test]
+    fn dump_entries_keep_newlines_and_equal_signs() {
+        let dump = parse_env_dump("A=one\ntwo\0B=x=y\0\0NOT_AN_ENTRY\0EMPTY=\0");
+        assert_eq!(dump.len(), 3);
+        assert_eq!(dump["A"], "one\ntwo");
+        assert_eq!(dump["B"], "x=y");
+        assert_eq!(dump["EMPTY"], "");
+    }
+
+    #[test]
+    fn variables_missing_from_the_dump_become_script_tombstones() {
+        let mut env = environment(&[("KEPT", "1"), ("UNSET", "2"), ("not-an-identifier", "3")]);
+        env.load_script_env("KEPT=1\0ADDED=4\0");
+
+        let variables = env.get_variables();
+        assert_eq!(variables["KEPT"].source, EnvVariableSource::Global);
+        assert_eq!(env.get_value("UNSET"), None);
+        assert!(variables["UNSET"].removed);
+        assert_eq!(variables["UNSET"].source, EnvVariableSource::Script);
+        // Shells drop the variables they cannot represent, they were not unset.
+        assert_eq!(env.get_value("not-an-identifier"), Some("3"));
+        assert_eq!(env.get_value("ADDED"), Some("4"));
+        assert_eq!(variables["ADDED"].source, EnvVariableSource::Script);
+    }
+
+    #[test]
+    fn tombstones_survive_merges_until_the_variable_is_set_again() {
+        let mut env = environment(&[("UNSET", "2")]);
+        env.load_script_env("OTHER=1\0");
+
+        let mut merged = environment(&[("UNSET", "2")]);
+        merged.merge_env(env.clone());
+        assert_eq!(merged.get_value("UNSET"), None);
+
+        env.load_script_env("OTHER=1\0UNSET=back\0");
+        assert_eq!(env.get_value("UNSET"), Some("back"));
+    }
+
+    #[test]
+    fn an_empty_dump_changes_nothing() {
+        let mut env = environment(&[("KEPT", "1")]);
+        env.load_script_env("");
+        assert_eq!(env.get_value("KEPT"), Some("1"));
+    }
 }
@@ -1,5 +1,9 @@
+use std::time::Duration;
+
 use thiserror::Error;
 
+use crate::diagnostic::{Diagnostic, render};
+
 #[derive(Debug, Error)]
 pub enum RunnerError {
     #[error("IO error: {0}")]
@@ -8,9 +12,25 @@ pub enum RunnerError {
     #[error("YAML parse error: {0}")]
     Yaml(#[from] serde_yaml::Error),
 
+    #[error("Invalid configuration:\n{}", render(.0))]
+    Config(Vec<Diagnostic>),
+
     #[error("Constraints error: {0}")]
     Constraints(String),
 
     #[error("Command failed: {0}")]
     CmdFailed(String),
+
+    /// A step ran and exited unsuccessfully, `exit_code` being unset when a signal ended it.
+    #[error("Command failed: {message}")]
+    StepFailed {
+        message: String,
+        exit_code: Option<i32>,
+    },
+
+    #[error("Timed out: '{owner}' after {:.1}s", .elapsed.as_secs_f64())]
+    TimedOut { owner: String, elapsed: Duration },
+
+    #[error("Interrupted")]
+    Interrupted,
 }
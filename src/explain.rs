@@ -0,0 +1,98 @@
+use std::fmt::Write;
+
+use crate::environment::{EnvChange, EnvVariable, EnvVariableSource, Environment};
+
+fn describe_value(value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("'{value}'"),
+        None => "unset".to_string(),
+    }
+}
+
+fn current_value(variable: &EnvVariable) -> Option<&str> {
+    (!variable.removed).then_some(variable.value.as_str())
+}
+
+/// Lists the variables of `env` with the layer their value comes from and the layers it
+/// overrode. Inherited variables nobody touched are only listed with `all`.
+pub fn describe_environment(env: &Environment, all: bool) -> String {
+    let mut keys: Vec<&String> = env
+        .get_variables()
+        .iter()
+        .filter(|(_, variable)| {
+            all || variable.source != EnvVariableSource::Default || !variable.history.is_empty()
+        })
+        .map(|(key, _)| key)
+        .collect();
+    keys.sort();
+
+    let mut out = String::new();
+    for key in keys {
+        let variable = &env.get_variables()[key];
+        let _ = write!(
+            out,
+            "{key}={} [{}]",
+            current_value(variable).unwrap_or_default(),
+            variable.source.name()
+        );
+        if variable.removed {
+            out += " (unset)";
+        }
+        let overridden: Vec<&'static str> = variable
+            .history
+            .iter()
+            .filter(|change| change.overridden)
+            .map(|change| change.source.name())
+            .collect();
+        if !overridden.is_empty() {
+            let _ = write!(out, ", overrides {}", overridden.join(", "));
+        }
+        let ignored: Vec<&'static str> = variable
+            .history
+            .iter()
+            .filter(|change| !change.overridden)
+            .map(|change| change.source.name())
+            .collect();
+        if !ignored.is_empty() {
+            let _ = write!(out, ", wins over {}", ignored.join(", "));
+        }
+        out += "\n";
+    }
+    out
+}
+
+fn describe_change(change: &EnvChange) -> String {
+    let outcome = if change.overridden {
+        "then overridden"
+    } else {
+        "ignored, a layer with a higher priority had already set it"
+    };
+    format!(
+        "{}: {} ({outcome})",
+        change.source.name(),
+        describe_value(change.value.as_deref())
+    )
+}
+
+/// Traces the values every layer gave `key`, the current one last.
+pub fn explain_variable(env: &Environment, key: &str) -> String {
+    let Some(variable) = env.get_variables().get(key) else {
+        return format!("{key} is not set by any layer\n");
+    };
+    let mut out = format!(
+        "{key} is {} from the {} layer\n",
+        describe_value(current_value(variable)),
+        variable.source.name()
+    );
+    for (step, change) in variable.history.iter().enumerate() {
+        let _ = writeln!(out, "  {}. {}", step + 1, describe_change(change));
+    }
+    let _ = writeln!(
+        out,
+        "  {}. {}: {} (current)",
+        variable.history.len() + 1,
+        variable.source.name(),
+        describe_value(current_value(variable))
+    );
+    out
+}
@@ -0,0 +1,322 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::{
+    environment::{EnvVariableSource, Environment},
+    parser::is_identifier,
+};
+
+/// Piece of a value, either literal text or a `${NAME}` / `${NAME:-default}` reference.
+enum Part<'v> {
+    Text(&'v str),
+    Reference {
+        name: &'v str,
+        default: Option<&'v str>,
+    },
+}
+
+/// Splits `value` into literal text and references, `$${` standing for a literal `${`.
+fn parse(value: &str) -> Result<Vec<Part<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut text_start = 0;
+    let mut i = 0;
+    while let Some(offset) = value[i..].find('$') {
+        let dollar = i + offset;
+        let rest = &value[dollar..];
+        if rest.starts_with("$${") {
+            parts.push(Part::Text(&value[text_start..dollar]));
+            parts.push(Part::Text("${"));
+            i = dollar + 3;
+            text_start = i;
+            continue;
+        }
+        if !rest.starts_with("${") {
+            i = dollar + 1;
+            continue;
+        }
+
+        let body_start = dollar + 2;
+        let mut depth = 1;
+        let mut end = None;
+        for (pos, c) in value[body_start..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = Some(body_start + pos);
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let Some(end) = end else {
+            return Err(format!("'{rest}' misses its closing '}}'"));
+        };
+        let body = &value[body_start..end];
+        let (name, default) = match body.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (body, None),
+        };
+        if !is_identifier(name) {
+            return Err(format!(
+                "'${{{body}}}' does not name a variable, expected '${{NAME}}' or '${{NAME:-default}}'"
+            ));
+        }
+        if let Some(default) = default {
+            parse(default)?;
+        }
+        parts.push(Part::Text(&value[text_start..dollar]));
+        parts.push(Part::Reference { name, default });
+        i = end + 1;
+        text_start = i;
+    }
+    parts.push(Part::Text(&value[text_start..]));
+    Ok(parts)
+}
+
+/// Reports malformed references in `value` without resolving anything.
+pub(crate) fn check_syntax(value: &str) -> Result<(), String> {
+    parse(value).map(|_| ())
+}
+
+/// Names referenced by `value`, defaults included.
+fn references(value: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    for part in parse(value).unwrap_or_default() {
+        if let Part::Reference { name, default } = part {
+            names.push(name);
+            names.extend(default.map(references).unwrap_or_default());
+        }
+    }
+    names
+}
+
+/// Expands the references of `value`, `lookup` returning the value of a variable. As in the
+/// shell, the default of `${NAME:-default}` is used when the variable is unset or empty.
+fn expand(
+    value: &str,
+    lookup: &mut dyn FnMut(&str) -> Result<Option<String>, String>,
+) -> Result<String, String> {
+    let mut out = String::new();
+    for part in parse(value)? {
+        match part {
+            Part::Text(text) => out += text,
+            Part::Reference { name, default } => match (lookup(name)?, default) {
+                (Some(found), Some(_)) if !found.is_empty() => out += &found,
+                (Some(found), None) => out += &found,
+                (_, Some(default)) => out += &expand(default, lookup)?,
+                (None, None) => {
+                    return Err(format!(
+                        "'{name}' is not set, use '${{{name}:-default}}' to fall back on a default"
+                    ));
+                }
+            },
+        }
+    }
+    Ok(out)
+}
+
+/// Expands the references of a single value against `env`.
+pub(crate) fn interpolate(value: &str, env: &Environment) -> Result<String, String> {
+    expand(value, &mut |name| {
+        Ok(env.get_value(name).map(str::to_string))
+    })
+}
+
+/// Variables of one configuration layer being resolved against the environment they are
+/// added to.
+struct Layer<'l, 'e> {
+    vars: &'l HashMap<String, String>,
+    source: &'l EnvVariableSource,
+    env: &'l Environment<'e>,
+    resolved: HashMap<&'l str, String>,
+    /// Variables being resolved, to report the ones referencing each other.
+    stack: Vec<&'l str>,
+    /// Set when the error being returned comes from another variable and already names it.
+    nested_failure: bool,
+}
+
+impl<'l> Layer<'l, '_> {
+    fn resolve(&mut self, key: &'l str) -> Result<String, String> {
+        if let Some(value) = self.resolved.get(key) {
+            return Ok(value.clone());
+        }
+        if let Some(start) = self.stack.iter().position(|k| *k == key) {
+            let mut cycle = self.stack[start..].to_vec();
+            cycle.push(key);
+            return Err(format!(
+                "Variables reference each other: {}",
+                cycle.join(" -> ")
+            ));
+        }
+        let Some((key, raw)) = self.vars.get_key_value(key) else {
+            return Ok(String::new());
+        };
+        let key = key.as_str();
+        self.stack.push(key);
+        let value = expand(raw, &mut |name| self.lookup(key, name));
+        self.stack.pop();
+        let value = value.map_err(|e| {
+            if std::mem::take(&mut self.nested_failure) {
+                e
+            } else {
+                format!("{key}: {e}")
+            }
+        })?;
+        self.resolved.insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// A variable of the layer refers to the value the environment will have once the layer
+    /// is applied, its own name to the value it replaces.
+    fn lookup(&mut self, key: &str, name: &str) -> Result<Option<String>, String> {
+        let current = self.env.get_variables().get(name);
+        let layer_wins = current
+            .is_none_or(|variable| variable.source.get_priority() <= self.source.get_priority());
+        match self.vars.get_key_value(name) {
+            Some((name, _)) if name != key && layer_wins => {
+                let value = self.resolve(name);
+                self.nested_failure = value.is_err();
+                value.map(Some)
+            }
+            _ => Ok(self.env.get_value(name).map(str::to_string)),
+        }
+    }
+}
+
+/// Resolves the references of `vars`, a layer about to be added to `env` from `source`,
+/// returning its variables sorted by name.
+pub(crate) fn resolve_layer(
+    vars: &HashMap<String, String>,
+    source: &EnvVariableSource,
+    env: &Environment,
+) -> Result<Vec<(String, String)>, String> {
+    let mut layer = Layer {
+        vars,
+        source,
+        env,
+        resolved: HashMap::new(),
+        stack: Vec::new(),
+        nested_failure: false,
+    };
+    let mut keys: Vec<&String> = vars.keys().collect();
+    keys.sort();
+    keys.into_iter()
+        .map(|key| Ok((key.clone(), layer.resolve(key)?)))
+        .collect()
+}
+
+/// Adds the variables of a configuration layer to `env`, see [`resolve_layer`].
+pub(crate) fn apply_layer(
+    env: &mut Environment,
+    vars: &HashMap<String, String>,
+    source: EnvVariableSource,
+) -> Result<(), String> {
+    for (key, value) in resolve_layer(vars, &source, env)? {
+        env.upsert_variable(key, value, source.clone());
+    }
+    Ok(())
+}
+
+/// Returns the first loop of variables of `vars` referencing each other, as a path starting
+/// and ending with the same variable. A variable referencing itself means the value it
+/// replaces and is not a loop.
+pub(crate) fn find_reference_cycle(vars: &HashMap<String, String>) -> Option<Vec<String>> {
+    fn visit<'v>(
+        vars: &'v HashMap<String, String>,
+        key: &'v str,
+        path: &mut Vec<&'v str>,
+        finished: &mut BTreeSet<&'v str>,
+    ) -> Option<Vec<String>> {
+        if let Some(start) = path.iter().position(|k| *k == key) {
+            let mut cycle: Vec<String> = path[start..].iter().map(|k| k.to_string()).collect();
+            cycle.push(key.to_string());
+            return Some(cycle);
+        }
+        if finished.contains(key) {
+            return None;
+        }
+        path.push(key);
+        for name in references(&vars[key]) {
+            if name == key {
+                continue;
+            }
+            if let Some((name, _)) = vars.get_key_value(name)
+                && let Some(cycle) = visit(vars, name, path, finished)
+            {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        finished.insert(key);
+        None
+    }
+
+    let mut keys: Vec<&str> = vars.keys().map(String::as_str).collect();
+    keys.sort();
+    let mut finished = BTreeSet::new();
+    keys.into_iter()
+        .find_map(|key| visit(vars, key, &mut Vec::new(), &mut finished))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn defaults_apply_to_unset_and_empty_variables() {
+        let mut env = Environment::default();
+        env.upsert_variable("SET".into(), "value".into(), EnvVariableSource::Global);
+        env.upsert_variable("EMPTY".into(), String::new(), EnvVariableSource::Global);
+
+        assert_eq!(interpolate("${SET:-d}", &env).unwrap(), "value");
+        assert_eq!(interpolate("${EMPTY:-d}", &env).unwrap(), "d");
+        assert_eq!(interpolate("${UNSET:-d}", &env).unwrap(), "d");
+        assert_eq!(interpolate("${EMPTY}", &env).unwrap(), "");
+        assert_eq!(interpolate("${UNSET:-${SET}}", &env).unwrap(), "value");
+        assert!(interpolate("${UNSET}", &env).is_err());
+    }
+
+    #[test]
+    fn double_dollar_escapes_a_reference() {
+        let env = Environment::default();
+        assert_eq!(interpolate("$${HOME}", &env).unwrap(), "${HOME}");
+        assert_eq!(interpolate("a$b $${X:-y}", &env).unwrap(), "a$b ${X:-y}");
+        assert!(check_syntax("$${ not a reference").is_ok());
+    }
+
+    #[test]
+    fn malformed_references_are_reported() {
+        assert!(check_syntax("${NAME").is_err());
+        assert!(check_syntax("${1NAME}").is_err());
+        assert!(check_syntax("${A:-${B}").is_err());
+    }
+
+    #[test]
+    fn reference_cycles_are_found() {
+        let cycle = find_reference_cycle(&vars(&[("A", "${B}"), ("B", "${C:-${A}}"), ("C", "")]));
+        assert_eq!(cycle.unwrap(), ["A", "B", "A"]);
+
+        // A variable referencing itself extends the value it replaces.
+        assert_eq!(
+            find_reference_cycle(&vars(&[("PATH", "bin:${PATH}")])),
+            None
+        );
+    }
+
+    #[test]
+    fn layers_report_variables_referencing_each_other() {
+        let env = Environment::default();
+        let layer = vars(&[("A", "${B}"), ("B", "${A}")]);
+        let error = resolve_layer(&layer, &EnvVariableSource::Global, &env).unwrap_err();
+        assert!(error.contains("A -> B -> A"), "{error}");
+    }
+}
@@ -0,0 +1,85 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether Ctrl-C was pressed. The run then stops starting new steps and sections, only the
+/// hooks still run.
+pub(crate) fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Records the first Ctrl-C instead of exiting, the second one exits right away.
+fn on_interrupt() {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        platform::exit_now();
+    }
+}
+
+#[cfg(unix)]
+mod platform {
+    const SIGINT: i32 = 2;
+
+    unsafe extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        fn _exit(status: i32) -> !;
+    }
+
+    extern "C" fn handler(_: i32) {
+        super::on_interrupt();
+    }
+
+    pub(super) fn install() {
+        // SAFETY: the handler only touches an atomic, or exits through `_exit` which is
+        // async-signal-safe.
+        unsafe {
+            signal(SIGINT, handler);
+        }
+    }
+
+    pub(super) fn exit_now() -> ! {
+        // SAFETY: `_exit` ends the process without running anything else.
+        unsafe { _exit(130) }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    const CTRL_C_EVENT: u32 = 0;
+
+    unsafe extern "system" {
+        fn SetConsoleCtrlHandler(handler: extern "system" fn(u32) -> i32, add: i32) -> i32;
+    }
+
+    extern "system" fn handler(event: u32) -> i32 {
+        if event != CTRL_C_EVENT {
+            return 0;
+        }
+        super::on_interrupt();
+        1
+    }
+
+    pub(super) fn install() {
+        // SAFETY: the handler runs on a thread of its own and only touches an atomic.
+        unsafe {
+            SetConsoleCtrlHandler(handler, 1);
+        }
+    }
+
+    pub(super) fn exit_now() -> ! {
+        std::process::exit(130)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    pub(super) fn install() {}
+
+    pub(super) fn exit_now() -> ! {
+        std::process::exit(130)
+    }
+}
+
+/// Catches Ctrl-C for the rest of the process, see [`interrupted`].
+pub(crate) fn install() {
+    platform::install();
+}
@@ -0,0 +1,665 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+use log::warn;
+use serde_yaml::{Mapping, Value};
+
+use crate::{
+    config_model::{
+        Block, Config, GlobalConfig, LocalConfig, OPERATING_SYSTEMS, PlatformCommands, StepsMerge,
+    },
+    diagnostic::{ConfigPath, Diagnostic, Problem},
+    error::RunnerError,
+    parser::{parse_config_yaml, validate_config},
+};
+
+/// A configuration file, kept around to report problems against its content.
+struct SourceFile {
+    name: String,
+    canonical: PathBuf,
+    source: String,
+}
+
+/// Where each definition of a merged configuration comes from: the index of the loaded file
+/// and its path in that file, which differs for steps prepended or appended to inherited
+/// ones. A definition comes from where its closest recorded ancestor does.
+#[derive(Debug, Default, Clone)]
+struct Origins(HashMap<ConfigPath, (usize, ConfigPath)>);
+
+impl Origins {
+    /// Origins of a file read on its own.
+    fn of_file(file: usize) -> Self {
+        let root = ConfigPath::default();
+        Self(HashMap::from([(root.clone(), (file, root))]))
+    }
+
+    fn locate(&self, path: &ConfigPath) -> (usize, ConfigPath) {
+        let mut ancestor = Some(path.clone());
+        while let Some(current) = ancestor {
+            if let Some((file, source_path)) = self.0.get(&current) {
+                return (*file, path.rebase(&current, source_path));
+            }
+            ancestor = current.parent();
+        }
+        (0, path.clone())
+    }
+
+    fn file_of(&self, path: &ConfigPath) -> usize {
+        self.locate(path).0
+    }
+
+    /// Records that the definition at `to` is the one `from` has at `from_path`.
+    fn copy_moved(&mut self, to: &ConfigPath, from: &Origins, from_path: &ConfigPath) {
+        self.0.retain(|key, _| !key.starts_with(to));
+        for (key, origin) in &from.0 {
+            if key.starts_with(from_path) {
+                self.0.insert(key.rebase(from_path, to), origin.clone());
+            }
+        }
+        self.0.insert(to.clone(), from.locate(from_path));
+    }
+
+    /// Records that the definition at `path` comes from where `from` says it does.
+    fn copy(&mut self, path: &ConfigPath, from: &Origins) {
+        self.copy_moved(path, from, path);
+    }
+}
+
+/// Origins of the two configurations being merged.
+struct Merge<'m> {
+    base: &'m mut Origins,
+    top: &'m Origins,
+}
+
+impl Merge<'_> {
+    fn option<T>(&mut self, base: &mut Option<T>, top: Option<T>, path: &ConfigPath) {
+        if top.is_some() {
+            *base = top;
+            self.base.copy(path, self.top);
+        }
+    }
+
+    fn map<T>(
+        &mut self,
+        base: &mut Option<HashMap<String, T>>,
+        top: Option<HashMap<String, T>>,
+        path: &ConfigPath,
+    ) {
+        for (key, value) in top.into_iter().flatten() {
+            self.base.copy(&path.key(&key), self.top);
+            base.get_or_insert_default().insert(key, value);
+        }
+    }
+
+    /// Merges the global configuration `top` into `base` setting by setting, variables being
+    /// merged one by one.
+    fn global(&mut self, base: &mut GlobalConfig, top: GlobalConfig, path: &ConfigPath) {
+        self.option(
+            &mut base.execution_policy,
+            top.execution_policy,
+            &path.key("execution_policy"),
+        );
+        self.option(
+            &mut base.banned_sections,
+            top.banned_sections,
+            &path.key("skip_sections"),
+        );
+        self.option(
+            &mut base.carry_forward_silent,
+            top.carry_forward_silent,
+            &path.key("carry_forward_silent"),
+        );
+        self.option(
+            &mut base.bare_block_calls,
+            top.bare_block_calls,
+            &path.key("bare_block_calls"),
+        );
+        self.option(&mut base.session, top.session, &path.key("session"));
+        self.option(&mut base.shell, top.shell, &path.key("shell"));
+        self.option(&mut base.timeout, top.timeout, &path.key("timeout"));
+        self.option(
+            &mut base.timeout_grace,
+            top.timeout_grace,
+            &path.key("timeout_grace"),
+        );
+        self.map(&mut base.env, top.env, &path.key("env"));
+    }
+
+    fn local(&mut self, base: &mut LocalConfig, top: LocalConfig, path: &ConfigPath) {
+        self.option(
+            &mut base.execution_policy,
+            top.execution_policy,
+            &path.key("execution_policy"),
+        );
+        self.option(&mut base.shell, top.shell, &path.key("shell"));
+        self.map(&mut base.env, top.env, &path.key("env"));
+    }
+
+    /// Merges the block `top` over `base`, the block it overrides: settings are replaced one
+    /// by one and steps as `top.steps_merge` says.
+    fn block(&mut self, base: &mut Block, top: Block, path: &ConfigPath) {
+        let steps_path = path.key("steps");
+        let mode = top.steps_merge.unwrap_or_default();
+        match (&mut base.steps, top.steps) {
+            (Some(inherited), Some(steps)) if mode != StepsMerge::Replace => {
+                // Positions of the first inherited and the first new step once merged.
+                let (inherited_at, new_at) = match mode {
+                    StepsMerge::Prepend => (steps.len(), 0),
+                    _ => (0, inherited.len()),
+                };
+                let snapshot = self.base.clone();
+                for index in 0..inherited.len() {
+                    self.base.copy_moved(
+                        &steps_path.index(inherited_at + index),
+                        &snapshot,
+                        &steps_path.index(index),
+                    );
+                }
+                for index in 0..steps.len() {
+                    self.base.copy_moved(
+                        &steps_path.index(new_at + index),
+                        self.top,
+                        &steps_path.index(index),
+                    );
+                }
+                inherited.splice(new_at..new_at, steps);
+            }
+            (_, steps) => self.option(&mut base.steps, steps, &steps_path),
+        }
+        base.steps_merge = None;
+
+        if let Some(local) = top.local_config {
+            let path = path.key("config");
+            self.local(base.local_config.get_or_insert_default(), local, &path);
+        }
+        self.option(&mut base.session, top.session, &path.key("session"));
+        self.option(&mut base.parallel, top.parallel, &path.key("parallel"));
+        self.option(
+            &mut base.max_parallel,
+            top.max_parallel,
+            &path.key("max_parallel"),
+        );
+        self.option(
+            &mut base.shared_env,
+            top.shared_env,
+            &path.key("shared_env"),
+        );
+        self.map(&mut base.params, top.params, &path.key("params"));
+        self.option(&mut base.matrix, top.matrix, &path.key("matrix"));
+        self.option(&mut base.timeout, top.timeout, &path.key("timeout"));
+        self.option(&mut base.retries, top.retries, &path.key("retries"));
+        self.option(
+            &mut base.retry_delay,
+            top.retry_delay,
+            &path.key("retry_delay"),
+        );
+        self.option(
+            &mut base.retry_backoff,
+            top.retry_backoff,
+            &path.key("retry_backoff"),
+        );
+        self.option(
+            &mut base.retry_on_exit_codes,
+            top.retry_on_exit_codes,
+            &path.key("retry_on_exit_codes"),
+        );
+        self.option(&mut base.condition, top.condition, &path.key("if"));
+        self.option(
+            &mut base.on_failure,
+            top.on_failure,
+            &path.key("on_failure"),
+        );
+        self.option(&mut base.finally, top.finally, &path.key("finally"));
+    }
+
+    fn section(&mut self, base: &mut PlatformCommands, top: PlatformCommands, path: &ConfigPath) {
+        self.option(&mut base.needs, top.needs, &path.key("needs"));
+        let blocks = [
+            (&mut base.windows, top.windows),
+            (&mut base.linux, top.linux),
+            (&mut base.macos, top.macos),
+        ];
+        for ((base, top), os) in blocks.into_iter().zip(OPERATING_SYSTEMS) {
+            match (base, top) {
+                (Some(base), Some(top)) => self.block(base, top, &path.key(os)),
+                (base, top) => self.option(base, top, &path.key(os)),
+            }
+        }
+    }
+
+    /// Merges `top` over `base`, the configuration it extends, down to individual settings.
+    fn config(&mut self, base: &mut Config, top: Config) {
+        let root = ConfigPath::default();
+        self.option(&mut base.pipeline, top.pipeline, &root.key("pipeline"));
+        self.option(
+            &mut base.on_failure,
+            top.on_failure,
+            &root.key("on_failure"),
+        );
+        self.option(&mut base.finally, top.finally, &root.key("finally"));
+        for (name, commands) in top.tasks.0 {
+            let path = root.key("tasks").key(&name);
+            match base.tasks.0.get_mut(&name) {
+                Some(inherited) => self.section(inherited, commands, &path),
+                None => {
+                    self.base.copy(&path, self.top);
+                    base.tasks.0.insert(name, commands);
+                }
+            }
+        }
+        for (name, block) in top.blocks {
+            let path = root.key("blocks").key(&name);
+            match base.blocks.get_mut(&name) {
+                Some(inherited) => self.block(inherited, block, &path),
+                None => {
+                    self.base.copy(&path, self.top);
+                    base.blocks.insert(name, block);
+                }
+            }
+        }
+        if let Some(global_config) = top.global_config {
+            let base_config = base.global_config.get_or_insert_default();
+            self.global(base_config, global_config, &root.key("config"));
+        }
+    }
+}
+
+#[derive(Default)]
+struct Loader {
+    files: Vec<SourceFile>,
+    /// Canonical paths of the files being loaded, the innermost last.
+    stack: Vec<PathBuf>,
+}
+
+/// Reads the configuration in `path` along with the files it includes or extends, and
+/// validates the merged configuration. Problems are reported against the file the definition
+/// they are about comes from.
+pub fn load_config(path: &Path) -> Result<Config, RunnerError> {
+    let mut loader = Loader::default();
+    let source = fs::read_to_string(path)?;
+    let (config, origins) = loader.load(path, source)?;
+
+    let problems = validate_config(&config);
+    if problems.is_empty() {
+        return Ok(config);
+    }
+    let mut diagnostics: Vec<(usize, Diagnostic)> = problems
+        .into_iter()
+        .map(|mut problem| {
+            let (file, source_path) = origins.locate(&problem.path);
+            problem.path = source_path;
+            (file, loader.diagnostic(file, problem))
+        })
+        .collect();
+    diagnostics.sort_by_key(|(file, diagnostic)| (*file, diagnostic.location.map(|(l, _, _)| l)));
+    Err(RunnerError::Config(
+        diagnostics.into_iter().map(|(_, d)| d).collect(),
+    ))
+}
+
+/// Renders the merged configuration as YAML, mappings sorted by key and unset settings left
+/// out.
+pub fn effective_yaml(config: &Config) -> Result<String, RunnerError> {
+    fn tidy(value: Value, sort: bool) -> Option<Value> {
+        match value {
+            Value::Null => None,
+            Value::Sequence(items) => Some(Value::Sequence(
+                items
+                    .into_iter()
+                    .filter_map(|item| tidy(item, true))
+                    .collect(),
+            )),
+            Value::Mapping(mapping) => {
+                let mut entries: Vec<(Value, Value)> = mapping
+                    .into_iter()
+                    .filter_map(|(key, value)| {
+                        // The axes of a matrix keep their order, which is the order of the
+                        // combinations.
+                        let sort = key.as_str() != Some("matrix");
+                        Some((key, tidy(value, sort)?))
+                    })
+                    .collect();
+                if sort {
+                    entries.sort_by(|(a, _), (b, _)| a.as_str().cmp(&b.as_str()));
+                }
+                Some(Value::Mapping(entries.into_iter().collect::<Mapping>()))
+            }
+            value => Some(value),
+        }
+    }
+
+    let value = serde_yaml::to_value(config)?;
+    Ok(serde_yaml::to_string(
+        &tidy(value, true).unwrap_or_default(),
+    )?)
+}
+
+impl Loader {
+    fn diagnostic(&self, file: usize, problem: Problem) -> Diagnostic {
+        let file = &self.files[file];
+        Diagnostic::from_problem(problem, &file.source, &file.name)
+    }
+
+    fn error(&self, file: usize, problem: Problem) -> RunnerError {
+        RunnerError::Config(vec![self.diagnostic(file, problem)])
+    }
+
+    /// Parses the file at `path` and merges the files it includes under it, then the result
+    /// over the file it extends.
+    fn load(&mut self, path: &Path, source: String) -> Result<(Config, Origins), RunnerError> {
+        let index = self.files.len();
+        let name = path.display().to_string();
+        let mut config = match parse_config_yaml(&source) {
+            Ok(config) => config,
+            Err(RunnerError::Yaml(e)) => {
+                return Err(RunnerError::Config(vec![Diagnostic::from_yaml_error(
+                    &e, &source, &name,
+                )]));
+            }
+            Err(e) => return Err(e),
+        };
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        self.files.push(SourceFile {
+            name,
+            canonical: canonical.clone(),
+            source,
+        });
+
+        let mut merged = Config::default();
+        let mut origins = Origins::default();
+        let root = ConfigPath::default();
+        let includes = std::mem::take(&mut config.include);
+        let extends = config.extends.take();
+        self.stack.push(canonical);
+        for (position, include) in includes.iter().enumerate() {
+            let entry = root.key("include").index(position);
+            let (included, included_origins) = self.open(index, entry, path, include)?;
+            self.overlay(&mut merged, &mut origins, included, &included_origins, true);
+        }
+        self.overlay(
+            &mut merged,
+            &mut origins,
+            config,
+            &Origins::of_file(index),
+            false,
+        );
+        if let Some(parent) = extends {
+            let (mut base, mut base_origins) =
+                self.open(index, root.key("extends"), path, &parent)?;
+            Merge {
+                base: &mut base_origins,
+                top: &origins,
+            }
+            .config(&mut base, merged);
+            (merged, origins) = (base, base_origins);
+        }
+        self.stack.pop();
+        Ok((merged, origins))
+    }
+
+    /// Loads `relative`, named at `entry` of the file `from` with the given `index`.
+    fn open(
+        &mut self,
+        index: usize,
+        entry: ConfigPath,
+        from: &Path,
+        relative: &Path,
+    ) -> Result<(Config, Origins), RunnerError> {
+        let path = from.parent().unwrap_or(Path::new("")).join(relative);
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if let Some(start) = self.stack.iter().position(|file| *file == canonical) {
+            let mut cycle: Vec<String> = self.stack[start..]
+                .iter()
+                .map(|file| file.display().to_string())
+                .collect();
+            cycle.push(canonical.display().to_string());
+            return Err(self.error(
+                index,
+                Problem::new(
+                    entry,
+                    format!("Files include or extend each other: {}", cycle.join(" -> ")),
+                ),
+            ));
+        }
+        match fs::read_to_string(&path) {
+            Ok(source) => self.load(&path, source),
+            Err(e) => Err(self.error(
+                index,
+                Problem::new(entry, format!("Cannot read '{}': {e}", path.display())),
+            )),
+        }
+    }
+
+    /// Merges `top` into `base`, the blocks and sections of `top` replacing the ones of `base`
+    /// as a whole. With `warn`, definitions replacing one of another file are reported.
+    fn overlay(
+        &self,
+        base: &mut Config,
+        base_origins: &mut Origins,
+        top: Config,
+        top_origins: &Origins,
+        warn: bool,
+    ) {
+        let root = ConfigPath::default();
+        let replaced = |kind: &str, name: &str, path: &ConfigPath, origins: &Origins| {
+            let old = &self.files[origins.file_of(path)];
+            let new = &self.files[top_origins.file_of(path)];
+            // A file included twice replaces its own definitions.
+            if warn && old.canonical != new.canonical {
+                warn!(
+                    "{}",
+                    format!(
+                        "{kind} '{name}' of {} is replaced by the one of {}",
+                        old.name, new.name
+                    )
+                    .yellow()
+                );
+            }
+        };
+
+        let mut merge = Merge {
+            base: base_origins,
+            top: top_origins,
+        };
+        merge.option(&mut base.pipeline, top.pipeline, &root.key("pipeline"));
+        merge.option(
+            &mut base.on_failure,
+            top.on_failure,
+            &root.key("on_failure"),
+        );
+        merge.option(&mut base.finally, top.finally, &root.key("finally"));
+        let mut sections: Vec<_> = top.tasks.0.into_iter().collect();
+        sections.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (name, commands) in sections {
+            let path = root.key("tasks").key(&name);
+            if base.tasks.0.contains_key(&name) {
+                replaced("Section", &name, &path, merge.base);
+            }
+            base.tasks.0.insert(name, commands);
+            merge.base.copy(&path, top_origins);
+        }
+        let mut blocks: Vec<_> = top.blocks.into_iter().collect();
+        blocks.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (name, block) in blocks {
+            let path = root.key("blocks").key(&name);
+            if base.blocks.contains_key(&name) {
+                replaced("Block", &name, &path, merge.base);
+            }
+            base.blocks.insert(name, block);
+            merge.base.copy(&path, top_origins);
+        }
+        if let Some(global_config) = top.global_config {
+            let base_config = base.global_config.get_or_insert_default();
+            merge.global(base_config, global_config, &root.key("config"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static DIRECTORIES: AtomicUsize = AtomicUsize::new(0);
+
+    /// Writes `files` to a fresh directory, returning the path of the first one.
+    fn write(files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "zbuild-loader-{}-{}",
+            std::process::id(),
+            DIRECTORIES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        dir.join(files[0].0)
+    }
+
+    fn steps(config: &Config, block: &str) -> Vec<String> {
+        config.blocks[block]
+            .steps
+            .iter()
+            .flatten()
+            .map(|step| step.label())
+            .collect()
+    }
+
+    fn load_error(path: &Path) -> String {
+        match load_config(path) {
+            Err(RunnerError::Config(diagnostics)) => diagnostics[0].message.clone(),
+            other => panic!("expected a configuration error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn including_file_wins_over_later_includes_over_earlier_ones() {
+        let path = write(&[
+            (
+                "main.yml",
+                "include: [a.yml, b.yml]\nconfig:\n  env:\n    Y: main\nblocks:\n  c:\n    steps: [main c]\n",
+            ),
+            (
+                "a.yml",
+                "config:\n  env:\n    X: a\n    Y: a\nblocks:\n  b:\n    steps: [a b]\n  c:\n    steps: [a c]\n",
+            ),
+            (
+                "b.yml",
+                "blocks:\n  b:\n    steps: [b b]\n  d:\n    steps: [b d]\n",
+            ),
+        ]);
+        let config = load_config(&path).unwrap();
+        assert_eq!(steps(&config, "b"), ["b b"]);
+        assert_eq!(steps(&config, "c"), ["main c"]);
+        assert_eq!(steps(&config, "d"), ["b d"]);
+        let env = config.global_config.unwrap().env.unwrap();
+        assert_eq!(env["X"], "a");
+        assert_eq!(env["Y"], "main");
+    }
+
+    #[test]
+    fn include_cycles_are_rejected() {
+        let path = write(&[
+            ("main.yml", "include: [a.yml]\n"),
+            ("a.yml", "include: [b.yml]\n"),
+            ("b.yml", "include: [a.yml]\n"),
+        ]);
+        let error = load_error(&path);
+        assert!(
+            error.contains("Files include or extend each other"),
+            "{error}"
+        );
+        assert!(error.ends_with("a.yml"), "{error}");
+    }
+
+    #[test]
+    fn extending_file_settings_win_and_env_merges_key_by_key() {
+        let path = write(&[
+            (
+                "main.yml",
+                "extends: base.yml\nconfig:\n  execution_policy: carry_forward\n  env:\n    B: main\nblocks:\n  x:\n    config:\n      shell: bash\n",
+            ),
+            (
+                "base.yml",
+                "config:\n  execution_policy: fast_fail\n  env:\n    A: base\n    B: base\nblocks:\n  x:\n    steps: [base x]\n",
+            ),
+        ]);
+        let config = load_config(&path).unwrap();
+        let global = config.global_config.as_ref().unwrap();
+        assert_eq!(
+            global.execution_policy.as_ref(),
+            Some(&crate::config_model::ExecutionPolicy::CarryFroward)
+        );
+        let env = global.env.as_ref().unwrap();
+        assert_eq!(env["A"], "base");
+        assert_eq!(env["B"], "main");
+        // Blocks present on both sides are merged, their inherited steps being kept.
+        assert_eq!(steps(&config, "x"), ["base x"]);
+        assert!(config.blocks["x"].local_config.is_some());
+    }
+
+    #[test]
+    fn steps_merge_appends_prepends_or_replaces_inherited_steps() {
+        let path = write(&[
+            (
+                "main.yml",
+                "extends: base.yml\nblocks:\n  append:\n    steps_merge: append\n    steps: [top]\n  prepend:\n    steps_merge: prepend\n    steps: [top]\n  replace:\n    steps: [top]\n",
+            ),
+            (
+                "base.yml",
+                "blocks:\n  append:\n    steps: [base 1, base 2]\n  prepend:\n    steps: [base 1, base 2]\n  replace:\n    steps: [base 1, base 2]\n",
+            ),
+        ]);
+        let config = load_config(&path).unwrap();
+        assert_eq!(steps(&config, "append"), ["base 1", "base 2", "top"]);
+        assert_eq!(steps(&config, "prepend"), ["top", "base 1", "base 2"]);
+        assert_eq!(steps(&config, "replace"), ["top"]);
+    }
+
+    #[test]
+    fn extends_cycles_are_rejected() {
+        let path = write(&[
+            ("main.yml", "extends: base.yml\n"),
+            ("base.yml", "extends: main.yml\n"),
+        ]);
+        let error = load_error(&path);
+        assert!(
+            error.contains("Files include or extend each other"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn effective_yaml_sorts_keys_but_not_matrix_axes() {
+        let config = parse_config_yaml(
+            "blocks:\n  b:\n    steps: [make]\n    matrix:\n      rust: [stable]\n      os: [linux]\n",
+        )
+        .unwrap();
+        let yaml = effective_yaml(&config).unwrap();
+        let position = |text: &str| yaml.find(text).unwrap();
+        assert!(position("matrix:") < position("steps:"), "{yaml}");
+        assert!(position("rust:") < position("os:"), "{yaml}");
+    }
+
+    #[test]
+    fn steps_merge_without_an_extended_block_is_reported() {
+        let path = write(&[
+            (
+                "main.yml",
+                "extends: base.yml\nblocks:\n  merged:\n    steps_merge: append\n    steps: [top]\n  alone:\n    steps_merge: prepend\n    steps: [top]\n",
+            ),
+            ("base.yml", "blocks:\n  merged:\n    steps: [base]\n"),
+        ]);
+        let config = load_config(&path).unwrap();
+        assert_eq!(
+            crate::check::check_config(&config).warnings,
+            [
+                "`blocks.alone` sets `steps_merge` but overrides no block of an extended file, it has no effect"
+            ]
+        );
+    }
+}
@@ -5,53 +5,122 @@ use std::{
     path::PathBuf,
     process::{self},
 };
+mod check;
+mod condition;
 mod config_model;
+mod diagnostic;
 mod environment;
 mod error;
+mod explain;
+mod interpolate;
+mod interrupt;
+mod loader;
 mod parser;
+mod plan;
+mod process_group;
+mod report;
 mod runner;
+mod scheduler;
+mod session;
+mod shell;
 
 use crate::{
+    config_model::Config,
+    diagnostic::did_you_mean,
     environment::{EnvVariableSource, Environment},
     error::RunnerError,
-    runner::{Section, run},
+    runner::run,
 };
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Debug, Parser)]
 #[command(name = "zmake-tasks-runner", version, about)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+
     /// Path to YAML file. Defaults to ZMake.yml if not provided.
-    #[arg(value_name = "FILE", default_value = "ZMake.yml")]
+    #[arg(value_name = "FILE", default_value = "ZMake.yml", global = true)]
     file: PathBuf,
 
     /// Working directory to run commands in. Defaults to current directory.
-    #[arg(long = "cwd", value_name = "DIR")]
+    #[arg(long = "cwd", value_name = "DIR", global = true)]
     cwd: Option<PathBuf>,
 
     /// Override detected OS (advanced). By default detected from std::env::consts::OS.
-    #[arg(long = "os", value_enum)]
+    #[arg(long = "os", value_enum, global = true)]
     os: Option<OsChoice>,
-    #[arg(long = "section", value_enum)]
-    sections: Vec<Section>,
+
+    /// Run only the given sections of the pipeline. Can be repeated.
+    #[arg(long = "section", value_name = "SECTION", global = true)]
+    sections: Vec<String>,
+
+    /// Do not run the sections needed by the ones passed with --section.
+    #[arg(long = "no-deps", global = true)]
+    no_deps: bool,
+
+    /// Maximum number of independent sections running in parallel.
+    #[arg(
+        short = 'j',
+        long = "jobs",
+        value_name = "N",
+        default_value_t = 1,
+        global = true
+    )]
+    jobs: usize,
 
     /// Print the commands without executing them.
     #[arg(long = "dry-run")]
     dry_run: bool,
 
     /// Extra environment variables for child processes (KEY=VALUE). Can be repeated.
-    #[arg(long = "env", value_name = "KV", value_parser = parser::parse_kv)]
+    #[arg(long = "env", value_name = "KV", value_parser = parser::parse_kv, global = true)]
     envs: Vec<(String, String)>,
 
     /// Extra environment variables for child processes from a file (KEY=VALUE per line).
-    #[arg(long = "env-file", value_name = "FILE")]
+    #[arg(long = "env-file", value_name = "FILE", global = true)]
     env_file: Option<PathBuf>,
 
     /// Increase verbosity. Repeat for more detail (-v, -vv, -vvv).
-    #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count)]
+    #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count, global = true)]
     verbose: u8,
 }
 
+#[derive(Debug, Subcommand)]
+enum CliCommand {
+    /// Validate the configuration, including every block reference, without running anything.
+    Check,
+    /// Print what a run would do, expanding sections, blocks and their effective settings.
+    Plan {
+        /// Output format of the plan.
+        #[arg(long = "format", value_enum, default_value_t = PlanFormat::Text)]
+        format: PlanFormat,
+    },
+    /// Print the configuration once the files it includes or extends are merged into it.
+    Config,
+    /// Print the environment steps start with and the layer each variable comes from, for the
+    /// section given with --section if any.
+    Env {
+        /// Resolve the environment inside this block.
+        #[arg(long = "block", value_name = "BLOCK")]
+        block: Option<String>,
+
+        /// Trace every value the given variable went through.
+        #[arg(long = "why", value_name = "VAR")]
+        why: Option<String>,
+
+        /// Also list the inherited variables no layer changed.
+        #[arg(long = "all")]
+        all: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum PlanFormat {
+    Text,
+    Json,
+}
+
 #[derive(Debug, Clone, Copy, ValueEnum)]
 enum OsChoice {
     Windows,
@@ -59,14 +128,72 @@ enum OsChoice {
     Macos,
 }
 
+/// Exit code of a run that only failed in steps carried forward by the execution policy.
+const CARRIED_FAILURES_EXIT_CODE: i32 = 2;
+
+/// Exit code of a run stopped by Ctrl-C, as shells report it.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Exit code of a run stopped by a timeout, as `timeout(1)` reports it.
+const TIMED_OUT_EXIT_CODE: i32 = 124;
+
 fn main() {
-    if let Err(e) = real_main() {
-        error!("{}", format!("Error: {e}").red());
-        process::exit(1);
+    match real_main() {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            error!("{}", format!("Error: {e}").red());
+            process::exit(match e {
+                RunnerError::Interrupted => INTERRUPTED_EXIT_CODE,
+                RunnerError::TimedOut { .. } => TIMED_OUT_EXIT_CODE,
+                _ => 1,
+            });
+        }
     }
 }
 
-fn real_main() -> Result<(), RunnerError> {
+/// Applies the local configuration of the section requested with --section, then of `block`.
+fn scoped_environment<'a>(
+    config: &Config,
+    env: &Environment<'a>,
+    block: Option<&str>,
+) -> Result<Environment<'a>, RunnerError> {
+    let mut scoped = env.clone();
+    let sections = config.sections();
+    match env.sections.as_deref() {
+        None | Some([]) => {}
+        Some([id]) => {
+            let name = sections.name(*id);
+            let block = match config.tasks.0.get(name) {
+                Some(commands) => runner::commands_for_os(commands, name, &mut scoped, env.os)?,
+                None => None,
+            };
+            if block.is_none() {
+                warn!(
+                    "{}",
+                    format!("Section '{name}' has no {} steps", env.os).yellow()
+                );
+            }
+        }
+        Some(_) => {
+            return Err(RunnerError::Constraints(
+                "env describes a single section, pass --section once".to_string(),
+            ));
+        }
+    }
+    if let Some(name) = block {
+        let Some(block) = config.blocks.get(name) else {
+            let mut message = format!("Unknown block '{name}'");
+            if let Some(help) = did_you_mean(name, config.blocks.keys().map(String::as_str)) {
+                message = format!("{message}, {help}");
+            }
+            return Err(RunnerError::Constraints(message));
+        };
+        runner::apply_local_config(&mut scoped, block.local_config.as_ref(), name)?;
+    }
+    Ok(scoped)
+}
+
+fn real_main() -> Result<i32, RunnerError> {
     let mut cli = Cli::parse();
 
     let level = match cli.verbose {
@@ -80,9 +207,20 @@ fn real_main() -> Result<(), RunnerError> {
         let _ = env_logger::try_init();
     }
 
-    let yaml = fs::read_to_string(&cli.file)?;
+    let config = loader::load_config(&cli.file)?;
 
-    let config = parser::parse_yaml(&yaml)?;
+    if let Some(CliCommand::Check) = &cli.command {
+        info!(
+            "{}",
+            format!("{} is a valid configuration.", cli.file.display()).green()
+        );
+        return Ok(0);
+    }
+
+    if let Some(CliCommand::Config) = &cli.command {
+        print!("{}", loader::effective_yaml(&config)?);
+        return Ok(0);
+    }
 
     let detected_os = env::consts::OS;
 
@@ -99,7 +237,7 @@ fn real_main() -> Result<(), RunnerError> {
         None => detected_os,
     };
 
-    if detected_os != os {
+    if detected_os != os && cli.command.is_none() {
         warn!(
             "{}",
             format!(
@@ -116,7 +254,13 @@ fn real_main() -> Result<(), RunnerError> {
 
     let mut default_environment = Environment::default();
 
-    let _ = default_environment.capture_default_environment();
+    // Planning runs nothing but the read-only `git` lookup of `branch` conditions, not even
+    // the shell reporting the inherited variables.
+    if matches!(cli.command, Some(CliCommand::Plan { .. })) {
+        default_environment.inherit_process_environment();
+    } else {
+        let _ = default_environment.capture_default_environment();
+    }
 
     let mut global_environment = default_environment.clone();
 
@@ -124,55 +268,138 @@ fn real_main() -> Result<(), RunnerError> {
         if let Some(exec_policy) = &global_config.execution_policy {
             global_environment.execution_policy = exec_policy.clone();
         }
-        if let Some(env_vars) = &global_config.env {
-            for (key, value) in env_vars {
-                global_environment.upsert_variable(
-                    key.clone(),
-                    value.clone(),
-                    EnvVariableSource::Global,
-                );
-            }
+        if let Some(session) = global_config.session {
+            global_environment.session = session;
         }
+        if let Some(shell) = &global_config.shell {
+            global_environment.shell = Some(shell.clone());
+        }
+        global_environment.timeout_grace = global_config.timeout_grace;
     }
 
+    let mut passed_environment = Environment::default();
     if let Some(env_file) = cli.env_file {
         let content = fs::read_to_string(env_file)?;
-        global_environment.load_env(content, environment::EnvVariableSource::Passed);
+        passed_environment.load_env(content, environment::EnvVariableSource::Passed);
     }
-
     for (k, v) in cli.envs {
-        global_environment.upsert_variable(k, v, environment::EnvVariableSource::Passed);
+        passed_environment.upsert_variable(k, v, environment::EnvVariableSource::Passed);
     }
 
+    if let Some(env_vars) = config.global_config.as_ref().and_then(|g| g.env.as_ref()) {
+        // References see the variables passed on the command line, which win over the
+        // configuration.
+        let mut context = global_environment.clone();
+        context.merge_env(passed_environment.clone());
+        let resolved = interpolate::resolve_layer(env_vars, &EnvVariableSource::Global, &context)
+            .map_err(|e| {
+            RunnerError::Constraints(format!("Cannot resolve the global environment: {e}"))
+        })?;
+        for (key, value) in resolved {
+            global_environment.upsert_variable(key, value, EnvVariableSource::Global);
+        }
+    }
+    global_environment.merge_env(passed_environment);
+
     global_environment.os = os;
     global_environment.cwd = Some(cwd);
-    global_environment.sections = if cli.sections.is_empty() {
+    let sections = config.sections();
+    let mut requested = Vec::new();
+    for name in &cli.sections {
+        let Some(id) = sections.id(name) else {
+            let mut message = format!(
+                "Unknown section '{name}', declared sections are: {}",
+                sections.names().join(", ")
+            );
+            if let Some(help) = did_you_mean(name, sections.names().iter().copied()) {
+                message = format!("{message}, {help}");
+            }
+            return Err(RunnerError::Constraints(message));
+        };
+        requested.push(id);
+    }
+    global_environment.no_deps = cli.no_deps;
+    global_environment.jobs = cli.jobs;
+    global_environment.sections = if requested.is_empty() {
         None
     } else {
-        Some(cli.sections)
+        Some(requested)
     };
-    if let Some(global_config) = &config.global_config
-        && let Some(banned_sections) = &global_config.banned_sections
+    if let Some(global_config) = &config.global_config {
+        // Skipped sections are validated when parsing the configuration.
+        global_environment.banned_sections = global_config
+            .banned_sections
+            .as_ref()
+            .map(|banned| banned.iter().filter_map(|name| sections.id(name)).collect());
+    }
+
+    if let Some(CliCommand::Plan { format, .. }) = &cli.command {
+        let plan = plan::plan(&config, &global_environment)?;
+        match format {
+            PlanFormat::Text => print!("{}", plan.to_text()),
+            PlanFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(&plan).map_err(|e| RunnerError::CmdFailed(
+                    format!("failed to encode the plan: {e}")
+                ))?
+            ),
+        }
+        return Ok(0);
+    }
+
+    if let Some(CliCommand::Env {
+        block, why, all, ..
+    }) = &cli.command
     {
-        global_environment.banned_sections = Some(
-            banned_sections
-                .iter()
-                .map(|section| Section::get_section(Section::map_section(section)))
-                .collect(),
-        );
+        let env = scoped_environment(&config, &global_environment, block.as_deref())?;
+        match why {
+            Some(key) => print!("{}", explain::explain_variable(&env, key)),
+            None => print!("{}", explain::describe_environment(&env, *all)),
+        }
+        return Ok(0);
     }
 
+    if let Some(timeout) = config
+        .global_config
+        .as_ref()
+        .and_then(|global_config| global_config.timeout)
+    {
+        global_environment.limit_time("pipeline", timeout);
+    }
+    interrupt::install();
     match run(&config, &mut global_environment) {
         Ok(_) => {
-            info!(
+            let failures = global_environment.failures.messages();
+            if failures.is_empty() {
+                info!(
+                    "{}",
+                    format_args!("{}", "All tasks completed successfully.".green())
+                );
+                return Ok(0);
+            }
+
+            warn!(
                 "{}",
-                format_args!("{}", "All tasks completed successfully.".green())
+                format!(
+                    "Tasks completed with {} failure(s) carried forward:",
+                    failures.len()
+                )
+                .yellow()
             );
+            for failure in &failures {
+                warn!("{}", format!("  - {failure}").yellow());
+            }
+            let silent = config
+                .global_config
+                .as_ref()
+                .and_then(|global_config| global_config.carry_forward_silent)
+                .unwrap_or(false);
+            Ok(if silent {
+                0
+            } else {
+                CARRIED_FAILURES_EXIT_CODE
+            })
         }
-        Err(e) => {
-            return Err(e);
-        }
+        Err(e) => Err(e),
     }
-
-    Ok(())
 }
@@ -1,6 +1,11 @@
-use crate::config_model::{OPERATING_SYSTEMS, SECTIONS};
+use crate::check::check_config;
+use crate::config_model::{Block, OPERATING_SYSTEMS, PlatformCommands, Step, StepSpec};
+use crate::diagnostic::{ConfigPath, Problem, did_you_mean};
+use crate::scheduler::find_cycle;
 use crate::{config_model::Config, error::RunnerError};
-use std::collections::HashMap;
+use colored::Colorize;
+use log::warn;
+use std::collections::{HashMap, HashSet};
 
 pub fn parse_config_yaml(yaml: &str) -> Result<Config, RunnerError> {
     let cfg: Config = serde_yaml::from_str(yaml)?;
@@ -17,40 +22,259 @@ pub fn parse_kv(s: &str) -> Result<(String, String), String> {
     Ok((k.to_string(), v.to_string()))
 }
 
-fn validate_config(_config: &Config) -> Result<(), RunnerError> {
-    _config.blocks.iter().try_for_each(|(block_name, _)| {
-        if SECTIONS.contains(&block_name.as_str()) {
-            return Err(RunnerError::Constraints(format!(
-                "Block name '{block_name}' conflicts with reserved section name"
-            )));
+/// Splits a command line into words the way a POSIX shell would for simple quoting, returning
+/// `None` when a quote is left open.
+pub(crate) fn split_words(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
         }
+    }
+    if quote.is_some() {
+        return None;
+    }
+    words.extend(word);
+    Some(words)
+}
+
+pub(crate) fn is_identifier(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// A step resolved to a call of one of the configured blocks.
+pub(crate) struct BlockCall {
+    pub name: String,
+    pub args: HashMap<String, String>,
+}
+
+/// Resolves `task` to a block call: through `call:`, as `@block` followed by `key=value`
+/// arguments, or as a bare block name followed by arguments unless bare calls are disabled.
+pub(crate) fn block_call(config: &Config, step: &StepSpec, task: &str) -> Option<BlockCall> {
+    if let Some(name) = &step.call {
+        return Some(BlockCall {
+            name: name.clone(),
+            args: step.with.clone().unwrap_or_default(),
+        });
+    }
+    let explicit = task.strip_prefix('@');
+    if explicit.is_none()
+        && (!config.bare_block_calls() || task.starts_with('\'') || task.starts_with('"'))
+    {
+        return None;
+    }
+    let words = split_words(explicit.unwrap_or(task))?;
+    let (name, args) = words.split_first()?;
+    if explicit.is_none() && !config.blocks.contains_key(name) {
+        return None;
+    }
+    let args = args
+        .iter()
+        .map(|arg| {
+            arg.split_once('=')
+                .filter(|(key, _)| is_identifier(key))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+        })
+        .collect::<Option<HashMap<_, _>>>()?;
+    Some(BlockCall {
+        name: name.clone(),
+        args,
+    })
+}
+
+/// Lists every step of every section and block, hooks included, together with the name of
+/// its parent and its path in the configuration, parallel groups being flattened.
+pub(crate) fn all_steps(config: &Config) -> Vec<(&str, ConfigPath, &Step)> {
+    fn collect<'c>(
+        steps: &'c [Step],
+        parent: &'c str,
+        path: ConfigPath,
+        out: &mut Vec<(&'c str, ConfigPath, &'c Step)>,
+    ) {
+        for (index, step) in steps.iter().enumerate() {
+            let step_path = path.index(index);
+            out.push((parent, step_path.clone(), step));
+            if let Step::Parallel(group) = step {
+                collect(&group.parallel, parent, step_path.key("parallel"), out);
+            }
+        }
+    }
+
+    fn collect_block<'c>(
+        block: &'c Block,
+        parent: &'c str,
+        path: ConfigPath,
+        out: &mut Vec<(&'c str, ConfigPath, &'c Step)>,
+    ) {
+        let lists = [
+            ("steps", &block.steps),
+            ("on_failure", &block.on_failure),
+            ("finally", &block.finally),
+        ];
+        for (key, steps) in lists {
+            if let Some(steps) = steps {
+                collect(steps, parent, path.key(key), out);
+            }
+        }
+    }
+
+    let root = ConfigPath::default();
+    let mut out = Vec::new();
+    let mut sections: Vec<_> = config.tasks.0.iter().collect();
+    sections.sort_by_key(|(name, _)| *name);
+    for (name, commands) in sections {
+        for os in OPERATING_SYSTEMS {
+            if let Some(block) = commands.for_os(os) {
+                let path = root.key("tasks").key(name).key(os);
+                collect_block(block, name, path, &mut out);
+            }
+        }
+    }
+    let mut blocks: Vec<_> = config.blocks.iter().collect();
+    blocks.sort_by_key(|(name, _)| *name);
+    for (name, block) in blocks {
+        collect_block(block, name, root.key("blocks").key(name), &mut out);
+    }
+    let hooks = [
+        ("on_failure", &config.on_failure),
+        ("finally", &config.finally),
+    ];
+    for (key, steps) in hooks {
+        if let Some(steps) = steps {
+            collect(steps, "pipeline", root.key(key), &mut out);
+        }
+    }
+    out
+}
 
-        if OPERATING_SYSTEMS.contains(&block_name.as_str()) {
-            return Err(RunnerError::Constraints(format!(
-                "Block name '{block_name}' conflicts with reserved operating system name"
-            )));
+fn validate_pipeline(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let sections = config.sections();
+    let pipeline = sections.names();
+    let root = ConfigPath::default();
+    let mut declared = HashSet::new();
+    for (index, name) in pipeline.iter().enumerate() {
+        let path = root.key("pipeline").index(index);
+        if name.trim().is_empty() {
+            problems.push(Problem::new(
+                path,
+                "Pipeline contains an empty section name".to_string(),
+            ));
+        } else if OPERATING_SYSTEMS.contains(name) {
+            problems.push(Problem::new(
+                path,
+                format!("Section name '{name}' conflicts with reserved operating system name"),
+            ));
+        } else if !declared.insert(*name) {
+            problems.push(Problem::new(
+                path,
+                format!("Section '{name}' is declared twice in the pipeline"),
+            ));
         }
+    }
 
-        Ok(())
-    })?;
-    Ok(())
+    let mut sections: Vec<(&String, &PlatformCommands)> = config.tasks.0.iter().collect();
+    sections.sort_by_key(|(name, _)| *name);
+    for (name, commands) in &sections {
+        let path = root.key("tasks").key(name);
+        if !declared.contains(name.as_str()) {
+            problems.push(
+                Problem::new(
+                    path.clone(),
+                    format!(
+                        "Section '{name}' is not declared in the pipeline ({})",
+                        pipeline.join(", ")
+                    ),
+                )
+                .with_help(did_you_mean(name, pipeline.iter().copied())),
+            );
+        }
+        for (index, dependency) in commands.needs.iter().flatten().enumerate() {
+            if !declared.contains(dependency.as_str()) {
+                problems.push(
+                    Problem::new(
+                        path.key("needs").index(index),
+                        format!(
+                            "Section '{name}' needs '{dependency}' which is not declared in the pipeline"
+                        ),
+                    )
+                    .with_help(did_you_mean(dependency, pipeline.iter().copied())),
+                );
+            }
+        }
+    }
+
+    if let Some(cycle) = find_cycle(config) {
+        problems.push(Problem::new(
+            root.key("tasks").key(&cycle[0]).key("needs"),
+            format!("Sections depend on each other: {}", cycle.join(" -> ")),
+        ));
+    }
+
+    let banned = config
+        .global_config
+        .as_ref()
+        .and_then(|global_config| global_config.banned_sections.as_ref());
+    for (index, name) in banned.into_iter().flatten().enumerate() {
+        if !declared.contains(name.as_str()) {
+            problems.push(
+                Problem::new(
+                    root.key("config").key("skip_sections").index(index),
+                    format!(
+                        "Skipped section '{name}' is not declared in the pipeline ({})",
+                        pipeline.join(", ")
+                    ),
+                )
+                .with_help(did_you_mean(name, pipeline.iter().copied())),
+            );
+        }
+    }
+
+    problems
 }
 
-pub fn parse_yaml(yaml: &str) -> Result<Config, RunnerError> {
-    let config = parse_config_yaml(yaml);
-    match config {
-        Ok(cfg) => match validate_config(&cfg) {
-            Ok(_) => Ok(cfg),
-            Err(e) => Err(e),
-        },
-        Err(e) => Err(RunnerError::CmdFailed(format!(
-            "failed to parse YAML config: {e}"
-        ))),
+pub(crate) fn validate_config(_config: &Config) -> Vec<Problem> {
+    let mut problems = validate_pipeline(_config);
+    let sections = _config.sections();
+    let mut names: Vec<&String> = _config.blocks.keys().collect();
+    names.sort();
+    for block_name in names {
+        let path = ConfigPath::default().key("blocks").key(block_name);
+        if sections.contains(block_name) {
+            problems.push(Problem::new(
+                path,
+                format!("Block name '{block_name}' conflicts with reserved section name"),
+            ));
+        } else if OPERATING_SYSTEMS.contains(&block_name.as_str()) {
+            problems.push(Problem::new(
+                path,
+                format!("Block name '{block_name}' conflicts with reserved operating system name"),
+            ));
+        }
+    }
+
+    let report = check_config(_config);
+    for warning in &report.warnings {
+        warn!("{}", warning.yellow());
     }
+    problems.extend(report.errors);
+    problems
 }
 
-#[allow(dead_code)]
-fn parse_env_dump(content: &str) -> HashMap<String, String> {
+pub(crate) fn parse_env_dump(content: &str) -> HashMap<String, String> {
     let mut env_map = HashMap::new();
 
     for entry in content.split('\0') {
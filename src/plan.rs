@@ -0,0 +1,611 @@
+use std::{collections::BTreeMap, fmt::Write, path::PathBuf};
+
+use serde::Serialize;
+
+use crate::{
+    condition::Condition,
+    config_model::{Block, Config, ExecutionPolicy, Matrix, Retry, Shell, Step, combination_label},
+    environment::{EnvVariableSource, Environment},
+    error::RunnerError,
+    parser::block_call,
+    runner::{apply_local_config, commands_for_os, resolve_args, step_environment},
+    scheduler::{CLEAN_SECTION, ScheduledSection, schedule},
+};
+
+/// What a run would do, resolved without executing anything.
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub os: String,
+    /// Seconds the whole run may take.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    pub sections: Vec<PlannedSection>,
+    #[serde(flatten)]
+    pub hooks: PlannedHooks,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlannedSection {
+    pub name: String,
+    /// Why the section does not run, unset for the sections that do.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+    /// Sections this one waits for.
+    pub needs: Vec<String>,
+    pub policy: ExecutionPolicy,
+    pub session: bool,
+    pub parallel: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    #[serde(flatten)]
+    pub retry: PlannedRetry,
+    /// Combinations the steps run with, as `axis=value` pairs.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub matrix: Vec<String>,
+    pub steps: Vec<PlannedStep>,
+    #[serde(flatten)]
+    pub hooks: PlannedHooks,
+}
+
+/// Steps run once a section, a block or the whole run is over.
+#[derive(Debug, Default, Serialize)]
+pub struct PlannedHooks {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub on_failure: Vec<PlannedStep>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub finally: Vec<PlannedStep>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PlannedStep {
+    Command {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        run: String,
+        settings: StepSettings,
+    },
+    Block {
+        name: String,
+        args: BTreeMap<String, String>,
+        settings: StepSettings,
+        /// `if` expression of the block, decided when it runs.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        condition: Option<String>,
+        session: bool,
+        parallel: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timeout: Option<u64>,
+        #[serde(flatten)]
+        retry: PlannedRetry,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        matrix: Vec<String>,
+        steps: Vec<PlannedStep>,
+        #[serde(flatten)]
+        hooks: PlannedHooks,
+    },
+    Parallel {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        steps: Vec<PlannedStep>,
+    },
+}
+
+/// Effective settings of a step, once the global, block and step configuration are applied.
+#[derive(Debug, Serialize)]
+pub struct StepSettings {
+    pub policy: ExecutionPolicy,
+    pub cwd: Option<PathBuf>,
+    pub shell: Shell,
+    pub timeout: Option<u64>,
+    #[serde(flatten)]
+    pub retry: PlannedRetry,
+    pub continue_on_error: bool,
+    /// `if` expression of the step, decided when it runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub condition: Option<String>,
+    /// Variables set by the configuration or the command line, the inherited ones are left
+    /// out.
+    pub variables: BTreeMap<String, PlannedVariable>,
+}
+
+/// Retry settings of a step, a block or a section.
+#[derive(Debug, Default, Serialize)]
+pub struct PlannedRetry {
+    pub retries: u32,
+    /// Seconds waited before each retry, the backoff applied.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub retry_delays: Vec<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_on_exit_codes: Option<Vec<i32>>,
+}
+
+impl From<Retry<'_>> for PlannedRetry {
+    fn from(retry: Retry) -> Self {
+        let retry_delays = if retry.delay > 0 {
+            (1..=retry.retries)
+                .map(|number| retry.delay_before(number).as_secs())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        PlannedRetry {
+            retries: retry.retries,
+            retry_delays,
+            retry_on_exit_codes: retry.on_exit_codes.map(<[i32]>::to_vec),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlannedVariable {
+    /// Unset for variables removed before the step runs.
+    pub value: Option<String>,
+    pub source: EnvVariableSource,
+}
+
+/// Resolves the sections, platform blocks, block calls and skip rules of a run from `env`.
+///
+/// Variables exported by the steps themselves are only known once they ran, every section
+/// is planned from the environment the run starts with.
+pub fn plan(config: &Config, env: &Environment) -> Result<Plan, RunnerError> {
+    let scheduled = schedule(config, env);
+    let registry = config.sections();
+    let mut sections = Vec::new();
+    for (id, name) in registry.ids().zip(registry.names()) {
+        if let Some(section) = scheduled.iter().find(|section| section.name == *name) {
+            sections.push(plan_section(section, &scheduled, config, env)?);
+            continue;
+        }
+        let reason = if env.sections.is_some() {
+            "not requested"
+        } else if *name == CLEAN_SECTION {
+            "only runs when requested with --section"
+        } else if env
+            .banned_sections
+            .as_ref()
+            .is_some_and(|banned| banned.contains(&id))
+        {
+            "listed in skip_sections"
+        } else {
+            "not scheduled"
+        };
+        sections.push(skipped_section(name, reason.to_string(), env));
+    }
+
+    Ok(Plan {
+        os: env.os.to_string(),
+        timeout: config
+            .global_config
+            .as_ref()
+            .and_then(|global_config| global_config.timeout),
+        sections,
+        hooks: plan_hooks(
+            config.on_failure.as_deref(),
+            config.finally.as_deref(),
+            config,
+            env,
+        )?,
+    })
+}
+
+/// Plans a scheduled section, skipped when it has no block for the OS or its `if` does not hold.
+fn plan_section(
+    section: &ScheduledSection,
+    scheduled: &[ScheduledSection],
+    config: &Config,
+    env: &Environment,
+) -> Result<PlannedSection, RunnerError> {
+    let mut section_env = env.clone();
+    let needs = section
+        .needs
+        .iter()
+        .map(|index| scheduled[*index].name.to_string())
+        .collect();
+    let block = match section.commands {
+        Some(commands) => commands_for_os(commands, section.name, &mut section_env, env.os)?,
+        None => None,
+    };
+    let condition = match block.and_then(|block| block.condition.as_deref()) {
+        Some(source) => Some(
+            Condition::parse(source)
+                .map(|condition| (source, condition.holds(&section_env)))
+                .map_err(|e| {
+                    RunnerError::Constraints(format!("Invalid `if` of '{}': {e}", section.name))
+                })?,
+        ),
+        None => None,
+    };
+    Ok(match (block, condition) {
+        (_, Some((source, false))) => {
+            skipped_section(section.name, format!("`if: {source}` does not hold"), env)
+        }
+        (Some(block), _) => PlannedSection {
+            name: section.name.to_string(),
+            skipped: None,
+            needs,
+            policy: section_env.execution_policy.clone(),
+            session: uses_session(block, &section_env),
+            parallel: block.parallel.unwrap_or(false),
+            timeout: block.timeout,
+            retry: block.retry().into(),
+            matrix: matrix_labels(block),
+            steps: plan_steps(
+                block.steps.as_deref().unwrap_or_default(),
+                config,
+                &section_env,
+            )?,
+            hooks: plan_hooks(
+                block.on_failure.as_deref(),
+                block.finally.as_deref(),
+                config,
+                &section_env,
+            )?,
+        },
+        (None, _) => skipped_section(section.name, format!("no {} steps", env.os), env),
+    })
+}
+
+fn skipped_section(name: &str, reason: String, env: &Environment) -> PlannedSection {
+    PlannedSection {
+        name: name.to_string(),
+        skipped: Some(reason),
+        needs: Vec::new(),
+        policy: env.execution_policy.clone(),
+        session: false,
+        parallel: false,
+        timeout: None,
+        retry: PlannedRetry::default(),
+        matrix: Vec::new(),
+        steps: Vec::new(),
+        hooks: PlannedHooks::default(),
+    }
+}
+
+fn uses_session(block: &Block, env: &Environment) -> bool {
+    block.session.unwrap_or(env.session) && env.resolved_shell().session_program(env.os).is_some()
+}
+
+/// Lists the combinations of the matrix of `block` as `axis=value` pairs. The steps are
+/// planned once, without the values of any combination.
+fn matrix_labels(block: &Block) -> Vec<String> {
+    block
+        .matrix
+        .iter()
+        .flat_map(Matrix::combinations)
+        .map(|combination| combination_label(&combination))
+        .collect()
+}
+
+fn render_matrix(out: &mut String, matrix: &[String], indent: &str) {
+    for label in matrix {
+        let _ = writeln!(out, "{indent}matrix: {label}");
+    }
+}
+
+fn settings(env: &Environment, retry: Retry, continue_on_error: Option<bool>) -> StepSettings {
+    StepSettings {
+        policy: env.execution_policy.clone(),
+        cwd: env.cwd.clone(),
+        shell: env.resolved_shell(),
+        timeout: None,
+        retry: retry.into(),
+        continue_on_error: continue_on_error.unwrap_or(false),
+        condition: None,
+        variables: env
+            .get_variables()
+            .iter()
+            .filter(|(_, variable)| variable.source != EnvVariableSource::Default)
+            .map(|(key, variable)| {
+                (
+                    key.clone(),
+                    PlannedVariable {
+                        value: (!variable.removed).then(|| variable.value.clone()),
+                        source: variable.source.clone(),
+                    },
+                )
+            })
+            .collect(),
+    }
+}
+
+fn plan_steps(
+    steps: &[Step],
+    config: &Config,
+    env: &Environment,
+) -> Result<Vec<PlannedStep>, RunnerError> {
+    let mut planned = Vec::new();
+    for step in steps {
+        if let Step::Parallel(group) = step {
+            planned.push(PlannedStep::Parallel {
+                name: group.name.clone(),
+                steps: plan_steps(&group.parallel, config, env)?,
+            });
+            continue;
+        }
+
+        let spec = step.spec();
+        let task = spec.call.as_deref().unwrap_or(spec.run.trim());
+        let step_env = step_environment(&spec, env)
+            .map_err(|e| RunnerError::Constraints(format!("Step '{task}' {e}")))?;
+        let mut step_settings = settings(&step_env, spec.retry(), spec.continue_on_error);
+        step_settings.timeout = spec.timeout;
+        step_settings.condition = spec.condition.clone();
+
+        let Some(call) = block_call(config, &spec, task) else {
+            planned.push(PlannedStep::Command {
+                name: spec.name.clone(),
+                run: task.to_string(),
+                settings: step_settings,
+            });
+            continue;
+        };
+        let Some(block) = config.blocks.get(&call.name) else {
+            return Err(RunnerError::CmdFailed(format!(
+                "Block '{}' not found",
+                call.name
+            )));
+        };
+        let mut block_env = step_env.clone();
+        apply_local_config(&mut block_env, block.local_config.as_ref(), &call.name)?;
+        for (key, value) in resolve_args(&call.name, block, &call.args, &block_env)? {
+            block_env.upsert_variable(key, value, EnvVariableSource::Script);
+        }
+        planned.push(PlannedStep::Block {
+            name: call.name.clone(),
+            args: call.args.into_iter().collect(),
+            settings: step_settings,
+            condition: block.condition.clone(),
+            session: uses_session(block, &block_env),
+            parallel: block.parallel.unwrap_or(false),
+            timeout: block.timeout,
+            retry: block.retry().into(),
+            matrix: matrix_labels(block),
+            steps: plan_steps(
+                block.steps.as_deref().unwrap_or_default(),
+                config,
+                &block_env,
+            )?,
+            hooks: plan_hooks(
+                block.on_failure.as_deref(),
+                block.finally.as_deref(),
+                config,
+                &block_env,
+            )?,
+        });
+    }
+    Ok(planned)
+}
+
+fn plan_hooks(
+    on_failure: Option<&[Step]>,
+    finally: Option<&[Step]>,
+    config: &Config,
+    env: &Environment,
+) -> Result<PlannedHooks, RunnerError> {
+    // Every step of a hook runs, whatever happens to the others.
+    let mut hook_env = env.clone();
+    hook_env.execution_policy = ExecutionPolicy::CarryFroward;
+    Ok(PlannedHooks {
+        on_failure: plan_steps(on_failure.unwrap_or_default(), config, &hook_env)?,
+        finally: plan_steps(finally.unwrap_or_default(), config, &hook_env)?,
+    })
+}
+
+fn policy_name(policy: &ExecutionPolicy) -> &'static str {
+    match policy {
+        ExecutionPolicy::FastFail => "fast_fail",
+        ExecutionPolicy::CarryFroward => "carry_forward",
+    }
+}
+
+fn shell_name(shell: &Shell) -> String {
+    match shell {
+        Shell::Profile(profile) => format!("{profile:?}").to_lowercase(),
+        Shell::Custom(custom) => custom.argv.join(" "),
+    }
+}
+
+fn render_retry(line: &mut String, retry: &PlannedRetry) {
+    if retry.retries == 0 {
+        return;
+    }
+    let _ = write!(line, ", retries: {}", retry.retries);
+    if !retry.retry_delays.is_empty() {
+        let delays: Vec<String> = retry
+            .retry_delays
+            .iter()
+            .map(|delay| format!("{delay}s"))
+            .collect();
+        let _ = write!(line, " (waiting {})", delays.join(", "));
+    }
+    if let Some(codes) = &retry.retry_on_exit_codes {
+        let codes: Vec<String> = codes.iter().map(i32::to_string).collect();
+        let _ = write!(line, ", on exit codes: {}", codes.join(", "));
+    }
+}
+
+fn render_settings(out: &mut String, settings: &StepSettings, indent: &str) {
+    let mut line = format!(
+        "{indent}policy: {}, shell: {}",
+        policy_name(&settings.policy),
+        shell_name(&settings.shell)
+    );
+    if let Some(cwd) = &settings.cwd {
+        let _ = write!(line, ", cwd: {}", cwd.display());
+    }
+    if let Some(timeout) = settings.timeout {
+        let _ = write!(line, ", timeout: {timeout}s");
+    }
+    render_retry(&mut line, &settings.retry);
+    if settings.continue_on_error {
+        line += ", continue_on_error";
+    }
+    if let Some(condition) = &settings.condition {
+        let _ = write!(line, ", if: {condition}");
+    }
+    let _ = writeln!(out, "{line}");
+    for (key, variable) in &settings.variables {
+        let source = variable.source.name();
+        let _ = match &variable.value {
+            Some(value) => writeln!(out, "{indent}{key}={value} ({source})"),
+            None => writeln!(out, "{indent}unset {key} ({source})"),
+        };
+    }
+}
+
+fn render_hooks(out: &mut String, hooks: &PlannedHooks, depth: usize) {
+    let indent = "  ".repeat(depth);
+    for (kind, steps) in [
+        ("on_failure", &hooks.on_failure),
+        ("finally", &hooks.finally),
+    ] {
+        if !steps.is_empty() {
+            let _ = writeln!(out, "{indent}{kind}:");
+            render_steps(out, steps, depth + 1);
+        }
+    }
+}
+
+fn render_steps(out: &mut String, steps: &[PlannedStep], depth: usize) {
+    let indent = "  ".repeat(depth);
+    for step in steps {
+        match step {
+            PlannedStep::Command {
+                name,
+                run,
+                settings,
+            } => {
+                let label = name.as_ref().map(|n| format!("[{n}] ")).unwrap_or_default();
+                let mut lines = run.lines();
+                let _ = writeln!(out, "{indent}$ {label}{}", lines.next().unwrap_or_default());
+                for line in lines {
+                    let _ = writeln!(out, "{indent}  {line}");
+                }
+                render_settings(out, settings, &format!("{indent}    "));
+            }
+            PlannedStep::Block {
+                name,
+                args,
+                settings,
+                condition,
+                session,
+                parallel,
+                timeout,
+                retry,
+                matrix,
+                steps,
+                hooks,
+            } => {
+                let mut line = format!("{indent}@{name}");
+                for (key, value) in args {
+                    let _ = write!(line, " {key}={value}");
+                }
+                if *session {
+                    line += " (session)";
+                }
+                if *parallel {
+                    line += " (parallel)";
+                }
+                if let Some(timeout) = timeout {
+                    let _ = write!(line, " (timeout: {timeout}s)");
+                }
+                if retry.retries > 0 {
+                    let mut retries = String::new();
+                    render_retry(&mut retries, retry);
+                    let _ = write!(line, " ({})", retries.trim_start_matches(", "));
+                }
+                if let Some(condition) = condition {
+                    let _ = write!(line, " if: {condition}");
+                }
+                let _ = writeln!(out, "{line}");
+                render_settings(out, settings, &format!("{indent}    "));
+                render_matrix(out, matrix, &format!("{indent}    "));
+                render_steps(out, steps, depth + 1);
+                render_hooks(out, hooks, depth + 1);
+            }
+            PlannedStep::Parallel { name, steps } => {
+                let _ = writeln!(
+                    out,
+                    "{indent}parallel{}",
+                    name.as_ref().map(|n| format!(" {n}")).unwrap_or_default()
+                );
+                render_steps(out, steps, depth + 1);
+            }
+        }
+    }
+}
+
+impl Plan {
+    /// Renders the plan as an indented tree, one line per step followed by its settings.
+    pub fn to_text(&self) -> String {
+        let mut out = format!("Plan for {}", self.os);
+        if let Some(timeout) = self.timeout {
+            let _ = write!(out, ", timeout: {timeout}s");
+        }
+        out += "\n";
+        for section in &self.sections {
+            let _ = write!(out, "\n[{}]", section.name);
+            if let Some(reason) = &section.skipped {
+                let _ = writeln!(out, " skipped: {reason}");
+                continue;
+            }
+            if !section.needs.is_empty() {
+                let _ = write!(out, " after {}", section.needs.join(", "));
+            }
+            if section.session {
+                out += " (session)";
+            }
+            if section.parallel {
+                out += " (parallel)";
+            }
+            let _ = write!(out, ", policy: {}", policy_name(&section.policy));
+            if let Some(timeout) = section.timeout {
+                let _ = write!(out, ", timeout: {timeout}s");
+            }
+            render_retry(&mut out, &section.retry);
+            out += "\n";
+            render_matrix(&mut out, &section.matrix, "    ");
+            render_steps(&mut out, &section.steps, 1);
+            render_hooks(&mut out, &section.hooks, 1);
+        }
+        if !self.hooks.on_failure.is_empty() || !self.hooks.finally.is_empty() {
+            out += "\n[pipeline]\n";
+            render_hooks(&mut out, &self.hooks, 1);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_config_yaml;
+
+    #[test]
+    fn sections_are_planned_in_pipeline_order() {
+        let config = parse_config_yaml(
+            "pipeline: [build, lint, test, clean]\ntasks:\n  build:\n    linux:\n      steps: [make]\n  test:\n    linux:\n      steps: [make test]\n",
+        )
+        .unwrap();
+        let mut env = Environment::default();
+        env.os = "linux";
+        env.sections = Some(vec![config.sections().id("test").unwrap()]);
+        env.no_deps = true;
+        let plan = plan(&config, &env).unwrap();
+        let sections: Vec<(&str, Option<&str>)> = plan
+            .sections
+            .iter()
+            .map(|section| (section.name.as_str(), section.skipped.as_deref()))
+            .collect();
+        assert_eq!(
+            sections,
+            [
+                ("build", Some("not requested")),
+                ("lint", Some("not requested")),
+                ("test", None),
+                ("clean", Some("not requested")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    io,
+    process::{Child, Command, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::interrupt;
+
+/// Interval at which a running command is checked for an expired deadline or Ctrl-C.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// What stops a running command before it ends on its own.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Watch {
+    /// Point in time after which the command is killed.
+    pub deadline: Option<Instant>,
+    /// Whether Ctrl-C stops the command.
+    pub interruptible: bool,
+    /// Time left to the command between the polite signal and `SIGKILL`.
+    pub grace: Duration,
+}
+
+/// Why a command was stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stop {
+    TimedOut,
+    Interrupted,
+}
+
+impl Watch {
+    /// Whether the command has to be stopped right now.
+    pub fn check(&self) -> Option<Stop> {
+        if self.interruptible && interrupt::interrupted() {
+            Some(Stop::Interrupted)
+        } else if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            Some(Stop::TimedOut)
+        } else {
+            None
+        }
+    }
+}
+
+/// Starts `cmd` in a process group of its own, so that everything it spawns can be stopped
+/// along with it. Ctrl-C no longer reaches the group from the terminal, [`wait`] forwards it.
+pub(crate) fn own_process_group(cmd: &mut Command) {
+    platform::own_process_group(cmd);
+}
+
+/// Waits for `child`, stopping its whole process group as soon as `watch` asks for it.
+pub(crate) fn wait(child: &mut Child, watch: Watch) -> io::Result<Result<ExitStatus, Stop>> {
+    if watch.deadline.is_none() && !watch.interruptible {
+        return child.wait().map(Ok);
+    }
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Ok(status));
+        }
+        if let Some(stop) = watch.check() {
+            terminate(child, stop, watch.grace);
+            return Ok(Err(stop));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Stops the process group of `child`: `SIGTERM` for a timeout, `SIGINT` for Ctrl-C, then
+/// `SIGKILL` for whatever is left once `grace` is over. Windows has no such signals, the
+/// whole process tree is killed right away.
+pub(crate) fn terminate(child: &mut Child, stop: Stop, grace: Duration) {
+    platform::signal_group(child, stop);
+    let until = Instant::now() + grace;
+    while Instant::now() < until {
+        if let Ok(Some(_)) = child.try_wait() {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    // Descendants may outlive the leader of the group, they get killed all the same.
+    kill(child);
+}
+
+/// Kills the process group of `child` at once.
+pub(crate) fn kill(child: &mut Child) {
+    platform::kill_group(child);
+    let _ = child.wait();
+}
+
+#[cfg(unix)]
+mod platform {
+    use super::Stop;
+    use std::{
+        os::unix::process::CommandExt,
+        process::{Child, Command},
+    };
+
+    const SIGINT: i32 = 2;
+    const SIGKILL: i32 = 9;
+    const SIGTERM: i32 = 15;
+
+    unsafe extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+    }
+
+    pub(super) fn own_process_group(cmd: &mut Command) {
+        cmd.process_group(0);
+    }
+
+    fn signal(child: &Child, sig: i32) {
+        let Ok(pid) = i32::try_from(child.id()) else {
+            return;
+        };
+        // SAFETY: signals the group led by our own child, which it was started as. The group
+        // being gone already is not an error worth reporting.
+        unsafe {
+            kill(-pid, sig);
+        }
+    }
+
+    pub(super) fn signal_group(child: &mut Child, stop: Stop) {
+        signal(
+            child,
+            match stop {
+                Stop::TimedOut => SIGTERM,
+                Stop::Interrupted => SIGINT,
+            },
+        );
+    }
+
+    pub(super) fn kill_group(child: &mut Child) {
+        signal(child, SIGKILL);
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::Stop;
+    use std::{
+        os::windows::process::CommandExt,
+        process::{Child, Command, Stdio},
+    };
+
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+
+    pub(super) fn own_process_group(cmd: &mut Command) {
+        cmd.creation_flags(CREATE_NEW_PROCESS_GROUP);
+    }
+
+    pub(super) fn signal_group(child: &mut Child, _: Stop) {
+        kill_group(child);
+    }
+
+    pub(super) fn kill_group(child: &mut Child) {
+        let _ = Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &child.id().to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        let _ = child.kill();
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    use super::Stop;
+    use std::process::{Child, Command};
+
+    pub(super) fn own_process_group(_: &mut Command) {}
+
+    pub(super) fn signal_group(child: &mut Child, _: Stop) {
+        kill_group(child);
+    }
+
+    pub(super) fn kill_group(child: &mut Child) {
+        let _ = child.kill();
+    }
+}
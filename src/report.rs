@@ -0,0 +1,60 @@
+use std::sync::{Arc, Mutex};
+
+/// Failures carried forward by the `carry_forward` policy, shared by every clone of an
+/// environment so that they can be reported once the whole run is over. The last failed step
+/// is kept as well, for the hooks running after it.
+#[derive(Debug, Default, Clone)]
+pub struct FailureLog(Arc<Mutex<Failures>>);
+
+#[derive(Debug, Default)]
+struct Failures {
+    messages: Vec<String>,
+    last_step: Option<FailedStep>,
+}
+
+/// A step that failed, as seen by the `on_failure` and `finally` hooks.
+#[derive(Debug, Clone)]
+pub struct FailedStep {
+    /// Name of the step, its command when it has none.
+    pub name: String,
+    /// Unset when the step did not exit on its own, for example when it timed out.
+    pub exit_code: Option<i32>,
+}
+
+impl FailureLog {
+    pub fn record(&self, message: String) {
+        if let Ok(mut failures) = self.0.lock() {
+            failures.messages.push(message);
+        }
+    }
+
+    pub fn messages(&self) -> Vec<String> {
+        self.0
+            .lock()
+            .map(|failures| failures.messages.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn record_step(&self, step: FailedStep) {
+        if let Ok(mut failures) = self.0.lock() {
+            failures.last_step = Some(step);
+        }
+    }
+
+    pub fn last_step(&self) -> Option<FailedStep> {
+        self.0
+            .lock()
+            .ok()
+            .and_then(|failures| failures.last_step.clone())
+    }
+
+    /// Copies the failures recorded here into `other`, their messages prefixed with `prefix`.
+    pub fn forward_to(&self, other: &FailureLog, prefix: &str) {
+        for message in self.messages() {
+            other.record(format!("{prefix}{message}"));
+        }
+        if let Some(step) = self.last_step() {
+            other.record_step(step);
+        }
+    }
+}
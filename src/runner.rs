@@ -1,390 +1,1365 @@
 use log::{error, info, warn};
 use std::{
+    collections::HashMap,
     path::PathBuf,
     process::{Command, ExitStatus, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
 };
 
 use colored::Colorize;
 
 use crate::{
-    config_model::{Config, ExecutionPolicy, PlatformCommands},
+    condition::Condition,
+    config_model::{
+        Block, Config, ExecutionPolicy, LocalConfig, Matrix, ParallelGroup, PlatformCommands,
+        Retry, Step, StepSpec, combination_label,
+    },
     environment::{EnvVariableSource, Environment},
     error::RunnerError,
+    interpolate::{apply_layer, interpolate},
+    interrupt,
+    parser::block_call,
+    process_group::{self, POLL_INTERVAL, Stop, Watch},
+    report::{FailedStep, FailureLog},
+    scheduler::schedule,
+    session::{Session, StepOutcome},
+    shell::{Capture, forward_prefixed, quote_posix, session_command, shell_command},
 };
-use clap::ValueEnum;
-
-#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq, Hash)]
-pub enum Section {
-    PreBuild,
-    Build,
-    PostBuild,
-    Test,
-    PreDeploy,
-    Deploy,
-    PostDeploy,
-    Clean,
-}
-
-impl Section {
-    pub fn as_str(&self) -> &'static str {
-        match self {
-            Section::PreBuild => "PreBuild",
-            Section::Build => "Build",
-            Section::PostBuild => "PostBuild",
-            Section::Test => "Test",
-            Section::PreDeploy => "PreDeploy",
-            Section::Deploy => "Deploy",
-            Section::PostDeploy => "PostDeploy",
-            Section::Clean => "Clean",
-        }
-    }
-
-    pub fn map_section(yml_name: &str) -> &str {
-        match yml_name {
-            "prebuild" => "PreBuild",
-            "build" => "Build",
-            "postbuild" => "PostBuild",
-            "test" => "Test",
-            "predeploy" => "PreDeploy",
-            "deploy" => "Deploy",
-            "postdeploy" => "PostDeploy",
-            "clean" => "Clean",
-            _ => "Unknown",
-        }
-    }
-
-    pub fn get_section(name: &str) -> Section {
-        match name {
-            "PreBuild" => Section::PreBuild,
-            "Build" => Section::Build,
-            "PostBuild" => Section::PostBuild,
-            "Test" => Section::Test,
-            "PreDeploy" => Section::PreDeploy,
-            "Deploy" => Section::Deploy,
-            "PostDeploy" => Section::PostDeploy,
-            "Clean" => Section::Clean,
-            _ => panic!("unknown section name: {name}"),
-        }
-    }
-}
-
-fn commands_for_os<'a>(
-    pc: &'a PlatformCommands,
-    env: &mut Environment<'a>,
+
+/// Applies the configuration of the section or block `owner` to `env`.
+pub(crate) fn apply_local_config(
+    env: &mut Environment,
+    local_config: Option<&LocalConfig>,
+    owner: &str,
+) -> Result<(), RunnerError> {
+    if let Some(local_config) = local_config {
+        if let Some(exec_policy) = &local_config.execution_policy {
+            env.execution_policy = exec_policy.clone();
+        }
+        if let Some(shell) = &local_config.shell {
+            env.shell = Some(shell.clone());
+        }
+        if let Some(env_vars) = &local_config.env {
+            apply_layer(env, env_vars, EnvVariableSource::Local).map_err(|e| {
+                RunnerError::Constraints(format!(
+                    "Cannot resolve the environment of '{owner}': {e}"
+                ))
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the block of the section `name` for `os`, its configuration being applied to `env`.
+pub(crate) fn commands_for_os<'c>(
+    pc: &'c PlatformCommands,
+    name: &str,
+    env: &mut Environment,
     os: &str,
-) -> Option<&'a Vec<String>> {
-    match os {
-        "windows" => {
-            if pc.windows.is_some() {
-                let current = pc.windows.as_ref().unwrap();
-                if current.local_config.is_some() {
-                    if let Some(env_vars) = &current.local_config.as_ref().unwrap().env {
-                        for (key, value) in env_vars {
-                            env.upsert_variable(
-                                key.to_string(),
-                                value.to_string(),
-                                EnvVariableSource::Local,
-                            );
-                        }
+) -> Result<Option<&'c Block>, RunnerError> {
+    let Some(block) = pc.for_os(os).filter(|block| block.steps.is_some()) else {
+        return Ok(None);
+    };
+    apply_local_config(env, block.local_config.as_ref(), name)?;
+    Ok(Some(block))
+}
+
+/// Evaluates the `if` expression of `owner` against `env`, an unset expression always holds.
+fn condition_holds(
+    condition: Option<&str>,
+    env: &Environment,
+    owner: &str,
+) -> Result<bool, String> {
+    let Some(source) = condition else {
+        return Ok(true);
+    };
+    let holds = Condition::parse(source)
+        .map_err(|e| format!("Invalid `if` of '{owner}': {e}"))?
+        .holds(env);
+    if !holds {
+        info!(
+            "{}",
+            format!("Skipping '{owner}', `if: {source}` does not hold").dimmed()
+        );
+    }
+    Ok(holds)
+}
+
+/// Whether a section block still runs once the run is stopping after a failure, which only
+/// the blocks checking the outcome of the earlier sections do.
+fn runs_after_failure(block: &Block) -> bool {
+    block
+        .condition
+        .as_deref()
+        .and_then(|source| Condition::parse(source).ok())
+        .is_some_and(|condition| condition.checks_status())
+}
+
+/// Runs the scheduled sections, up to `env.jobs` of them at once. A section starts from the
+/// environment left by every section finished so far.
+pub fn run(config: &Config, env: &mut Environment) -> Result<(), RunnerError> {
+    let sections = schedule(config, env);
+    let jobs = env.jobs.max(1);
+    let mut started = vec![false; sections.len()];
+    let mut finished = vec![false; sections.len()];
+    // Sections that failed or waited for one that did.
+    let mut failed = vec![false; sections.len()];
+    let mut logs: Vec<Option<FailureLog>> = vec![None; sections.len()];
+    let mut running = 0;
+    let mut failure: Option<RunnerError> = None;
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        loop {
+            if failure.is_none() {
+                failure = stop_before_step(env);
+            }
+            for (index, section) in sections.iter().enumerate() {
+                if running >= jobs {
+                    break;
+                }
+                if started[index] || !section.needs.iter().all(|n| finished[*n]) {
+                    continue;
+                }
+                started[index] = true;
+
+                // Once the run is stopping, only the sections asking for it still run.
+                let block = section.commands.and_then(|c| c.for_os(env.os));
+                if failure.is_some()
+                    && (interrupt::interrupted()
+                        || out_of_time(env)
+                        || !block.is_some_and(runs_after_failure))
+                {
+                    finished[index] = true;
+                    failed[index] = true;
+                    continue;
+                }
+
+                let mut section_environment = env.clone();
+                section_environment.earlier_failure =
+                    failure.is_some() || section.needs.iter().any(|n| failed[*n]);
+                failed[index] = section_environment.earlier_failure;
+                // Failures carried forward by the section are told apart from the others.
+                section_environment.failures = FailureLog::default();
+                logs[index] = Some(section_environment.failures.clone());
+                let block = section
+                    .commands
+                    .map(|c| commands_for_os(c, section.name, &mut section_environment, env.os))
+                    .map(|block| {
+                        let block = block?;
+                        let condition = block.and_then(|block| block.condition.as_deref());
+                        let holds = condition_holds(condition, &section_environment, section.name)
+                            .map_err(RunnerError::Constraints)?;
+                        Ok(block.filter(|_| holds))
+                    });
+                let block = match block {
+                    Some(Ok(Some(block))) => block,
+                    Some(Err(e)) => {
+                        // Reported like a failure of the section itself.
+                        running += 1;
+                        let _ = sender.send((index, section_environment.execution_policy, Err(e)));
+                        continue;
                     }
-                    if let Some(exec_policy) =
-                        &current.local_config.as_ref().unwrap().execution_policy
-                    {
-                        env.execution_policy = exec_policy.clone();
+                    _ => {
+                        finished[index] = true;
+                        continue;
                     }
-                }
-                current.steps.as_ref()
-            } else {
-                None
+                };
+
+                running += 1;
+                let sender = sender.clone();
+                scope.spawn(move || {
+                    let result = run_section(section.name, config, block, &section_environment);
+                    let _ = sender.send((index, section_environment.execution_policy, result));
+                });
             }
-        }
-        "linux" => {
-            if pc.linux.is_some() {
-                let current = pc.linux.as_ref().unwrap();
-                if current.local_config.is_some() {
-                    if let Some(env_vars) = &current.local_config.as_ref().unwrap().env {
-                        for (key, value) in env_vars {
-                            env.upsert_variable(
-                                key.to_string(),
-                                value.to_string(),
-                                EnvVariableSource::Local,
-                            );
-                        }
-                    }
-                    if let Some(exec_policy) =
-                        &current.local_config.as_ref().unwrap().execution_policy
+
+            if running == 0 {
+                break;
+            }
+            let Ok((index, policy, result)) = receiver.recv() else {
+                break;
+            };
+            running -= 1;
+            finished[index] = true;
+            if let Some(log) = logs[index].take() {
+                failed[index] |= !log.messages().is_empty();
+                log.forward_to(&env.failures, "");
+            }
+            failed[index] |= result.is_err();
+            let section_name = sections[index].name;
+            match result {
+                Ok(new_env) => {
+                    env.merge_env(new_env);
+                }
+                Err(e) => {
+                    if policy == ExecutionPolicy::CarryFroward
+                        && !interrupt::interrupted()
+                        && !out_of_time(env)
                     {
-                        env.execution_policy = exec_policy.clone();
+                        warn!(
+                            "{}",
+                            format!(
+                                "Section '{section_name}' failed, carrying forward because global execution policy is CarryForward",
+                            )
+                            .yellow()
+                        );
+                        env.failures
+                            .record(format!("Section '{section_name}' failed: {e}"));
+                    } else if failure.is_none() {
+                        failure = Some(e);
                     }
                 }
-                current.steps.as_ref()
+            }
+        }
+    });
+
+    let result = match failure {
+        _ if interrupt::interrupted() => Err(RunnerError::Interrupted),
+        Some(e) => Err(e),
+        None => Ok(env.clone()),
+    };
+    let hooks = Hooks {
+        on_failure: config.on_failure.as_deref(),
+        finally: config.finally.as_deref(),
+    };
+    let failures = env.failures.clone();
+    run_hooks(hooks, "pipeline", config, env, result, &failures).map(|_| ())
+}
+
+pub fn run_section<'a>(
+    section_name: &str,
+    config: &Config,
+    block: &Block,
+    env: &Environment<'a>,
+) -> Result<Environment<'a>, RunnerError> {
+    info!("{}", format!("----- [{section_name}] -----").blue());
+    run_block_steps(block, section_name, config, env)
+}
+
+/// Resolves the arguments of a call to `block`, filling in defaults and rejecting unknown or
+/// missing parameters.
+pub(crate) fn bind_params(
+    block_name: &str,
+    block: &Block,
+    args: &HashMap<String, String>,
+) -> Result<Vec<(String, String)>, RunnerError> {
+    let empty = HashMap::new();
+    let params = block.params.as_ref().unwrap_or(&empty);
+    let mut unknown: Vec<&String> = args.keys().filter(|k| !params.contains_key(*k)).collect();
+    if !unknown.is_empty() {
+        unknown.sort();
+        let mut declared: Vec<&String> = params.keys().collect();
+        declared.sort();
+        return Err(RunnerError::Constraints(format!(
+            "Block '{block_name}' has no parameter {}, it takes: {}",
+            unknown
+                .iter()
+                .map(|k| format!("'{k}'"))
+                .collect::<Vec<_>>()
+                .join(", "),
+            if declared.is_empty() {
+                "none".to_string()
             } else {
-                None
+                declared
+                    .iter()
+                    .map(|k| k.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
             }
+        )));
+    }
+
+    let mut bound = Vec::new();
+    let mut missing = Vec::new();
+    for (key, param) in params {
+        match args.get(key).or(param.default.as_ref()) {
+            Some(value) => bound.push((key.clone(), value.clone())),
+            None if param.required.unwrap_or(false) => missing.push(key.as_str()),
+            None => {}
         }
-        "macos" => {
-            if pc.macos.is_some() {
-                let current = pc.macos.as_ref().unwrap();
-                if current.local_config.is_some() {
-                    if let Some(env_vars) = &current.local_config.as_ref().unwrap().env {
-                        for (key, value) in env_vars {
-                            env.upsert_variable(
-                                key.to_string(),
-                                value.to_string(),
-                                EnvVariableSource::Local,
-                            );
-                        }
-                    }
-                    if let Some(exec_policy) =
-                        &current.local_config.as_ref().unwrap().execution_policy
-                    {
-                        env.execution_policy = exec_policy.clone();
-                    }
-                }
-                current.steps.as_ref()
+    }
+    if !missing.is_empty() {
+        missing.sort();
+        return Err(RunnerError::Constraints(format!(
+            "Block '{block_name}' is missing required parameter(s): {}",
+            missing.join(", ")
+        )));
+    }
+    Ok(bound)
+}
+
+/// Binds the arguments of a call to `block` and expands their references against `env`, the
+/// environment of the block.
+pub(crate) fn resolve_args(
+    block_name: &str,
+    block: &Block,
+    args: &HashMap<String, String>,
+    env: &Environment,
+) -> Result<Vec<(String, String)>, RunnerError> {
+    bind_params(block_name, block, args)?
+        .into_iter()
+        .map(|(key, value)| match interpolate(&value, env) {
+            Ok(value) => Ok((key, value)),
+            Err(e) => Err(RunnerError::Constraints(format!(
+                "Cannot resolve parameter '{key}' of block '{block_name}': {e}"
+            ))),
+        })
+        .collect()
+}
+
+pub fn run_block<'a>(
+    block_name: &str,
+    args: &HashMap<String, String>,
+    config: &Config,
+    env: &Environment<'a>,
+) -> Result<Environment<'a>, RunnerError> {
+    info!("{}", format!("--- [Block: {block_name}] ---").magenta());
+    let mut block_environment = env.clone();
+    let Some(block) = config.blocks.get(block_name) else {
+        return Err(RunnerError::CmdFailed(format!(
+            "Block '{block_name}' not found"
+        )));
+    };
+
+    // Arguments win over every other variable while the block runs, and only while it runs.
+    let params = apply_local_config(
+        &mut block_environment,
+        block.local_config.as_ref(),
+        block_name,
+    )
+    .and_then(|()| resolve_args(block_name, block, args, &block_environment));
+    let (params, result) = match params {
+        Ok(params) => {
+            for (key, value) in &params {
+                block_environment.upsert_variable(
+                    key.clone(),
+                    value.clone(),
+                    EnvVariableSource::Script,
+                );
+            }
+            let result =
+                match condition_holds(block.condition.as_deref(), &block_environment, block_name) {
+                    Ok(true) => run_block_steps(block, block_name, config, &block_environment),
+                    Ok(false) => Ok(block_environment.clone()),
+                    Err(e) => Err(RunnerError::Constraints(e)),
+                };
+            (params, result)
+        }
+        Err(e) => (Vec::new(), Err(e)),
+    };
+    let unscope = |block_environment: &mut Environment| {
+        for (key, value) in &params {
+            if block_environment.get_value(key) == Some(value.as_str()) {
+                block_environment.restore_variable(key, env);
+            }
+        }
+    };
+
+    match result {
+        Ok(new_env) => {
+            block_environment.merge_env(new_env);
+            unscope(&mut block_environment);
+            Ok(block_environment)
+        }
+        Err(e) => {
+            unscope(&mut block_environment);
+            if env.execution_policy == ExecutionPolicy::CarryFroward
+                && !stopped_by_interrupt(env)
+                && !out_of_time(env)
+            {
+                warn!("{}", e.to_string().yellow());
+                warn!("{}", format!("Block '{block_name}' failed silently, moving forward because the parent execution policy is CarryForward").yellow());
+                env.failures
+                    .record(format!("Block '{block_name}' failed: {e}"));
+                Ok(block_environment)
             } else {
-                None
+                error!("{}", e.to_string().red());
+                Err(e)
             }
         }
-        _ => None,
     }
 }
 
-pub fn run(config: &Config, env: &mut Environment) -> Result<(), RunnerError> {
-    let filter: Option<std::collections::HashSet<&'static str>> = env
-        .sections
-        .as_ref()
-        .map(|v| v.iter().map(|s| s.as_str()).collect());
-    for (section_name, commands) in config.tasks.ordered_sections() {
-        if let Some(ref filt) = filter {
-            if !filt.contains(&section_name) {
-                continue;
+/// Runs the steps of `block`, once per combination of its matrix if it has one and within its
+/// timeout, as many times as its retry settings allow, then its hooks.
+fn run_block_steps<'a>(
+    block: &Block,
+    block_name: &str,
+    config: &Config,
+    env: &Environment<'a>,
+) -> Result<Environment<'a>, RunnerError> {
+    let run_attempt = |attempt_env: &Environment<'a>| {
+        let mut limited_env;
+        let attempt_env = match block.timeout {
+            Some(timeout) => {
+                limited_env = attempt_env.clone();
+                limited_env.limit_time(block_name, timeout);
+                &limited_env
             }
-        } else if section_name == Section::Clean.as_str()
-            || (env.banned_sections.is_some()
-                && env
-                    .banned_sections
-                    .as_ref()
-                    .unwrap()
-                    .contains(&Section::get_section(section_name)))
-        {
+            None => attempt_env,
+        };
+        match &block.matrix {
+            Some(matrix) => run_matrix(matrix, block, block_name, config, attempt_env),
+            None => run_steps(block, block_name, config, attempt_env),
+        }
+    };
+    let hooks = Hooks {
+        on_failure: block.on_failure.as_deref(),
+        finally: block.finally.as_deref(),
+    };
+    if hooks.on_failure.is_none() && hooks.finally.is_none() {
+        return with_retries(block.retry(), block_name, env, run_attempt);
+    }
+
+    // The block gets its own log so that the failures it carries forward can be told apart.
+    let mut block_env = env.clone();
+    block_env.failures = FailureLog::default();
+    let result = with_retries(block.retry(), block_name, &block_env, run_attempt);
+    block_env.failures.forward_to(&env.failures, "");
+    run_hooks(hooks, block_name, config, env, result, &block_env.failures)
+}
+
+/// Steps run once a block or the whole run is over, see [`Block::finally`].
+struct Hooks<'c> {
+    on_failure: Option<&'c [Step]>,
+    finally: Option<&'c [Step]>,
+}
+
+/// Runs the hooks of `owner` once `result` is known: `on_failure` when it failed, carried
+/// failures forward in `failures` or was interrupted, then `finally`.
+///
+/// Hooks see the outcome and the last failed step as variables and run all their steps,
+/// even after Ctrl-C or once the time of `owner` is up, the changes they make to the
+/// environment being dropped. A failing hook fails `owner` unless it failed already, in which
+/// case the original error is kept and the hook failure is only reported.
+fn run_hooks<'a>(
+    hooks: Hooks,
+    owner: &str,
+    config: &Config,
+    env: &Environment<'a>,
+    result: Result<Environment<'a>, RunnerError>,
+    failures: &FailureLog,
+) -> Result<Environment<'a>, RunnerError> {
+    let outcome = if interrupt::interrupted() {
+        "interrupted"
+    } else if result.is_err() || !failures.messages().is_empty() {
+        "failure"
+    } else {
+        "success"
+    };
+    let mut hook_env = match &result {
+        Ok(result_env) => result_env.clone(),
+        Err(_) => env.clone(),
+    };
+    hook_env.execution_policy = ExecutionPolicy::CarryFroward;
+    hook_env.uninterruptible = true;
+    hook_env.deadline = None;
+    hook_env.upsert_variable(
+        "ZBUILD_OUTCOME".to_string(),
+        outcome.to_string(),
+        EnvVariableSource::Script,
+    );
+    if outcome != "success"
+        && let Some(step) = failures.last_step()
+    {
+        hook_env.upsert_variable(
+            "ZBUILD_FAILED_STEP".to_string(),
+            step.name,
+            EnvVariableSource::Script,
+        );
+        if let Some(code) = step.exit_code {
+            hook_env.upsert_variable(
+                "ZBUILD_FAILED_EXIT_CODE".to_string(),
+                code.to_string(),
+                EnvVariableSource::Script,
+            );
+        }
+    }
+
+    let lists = [("on_failure", hooks.on_failure), ("finally", hooks.finally)];
+    let mut hook_failures = Vec::new();
+    for (kind, steps) in lists {
+        let Some(steps) = steps else {
+            continue;
+        };
+        if kind == "on_failure" && outcome == "success" {
             continue;
         }
-        match commands {
-            Some(c) => {
-                let mut section_environment = env.clone();
-                let result = match commands_for_os(c, &mut section_environment, env.os) {
-                    Some(cmds) => run_section(section_name, config, cmds, &section_environment),
-                    None => {
-                        continue;
+        info!("{}", format!("--- [{kind}: {owner}] ---").magenta());
+        hook_env.failures = FailureLog::default();
+        if let Err(e) = run_tasks(steps, config, &hook_env, owner) {
+            hook_env.failures.record(e.to_string());
+        }
+        for message in hook_env.failures.messages() {
+            hook_failures.push(format!("The {kind} hook of '{owner}' failed: {message}"));
+        }
+    }
+
+    match result {
+        Ok(_) if !hook_failures.is_empty() => Err(RunnerError::CmdFailed(hook_failures.join("; "))),
+        Ok(result_env) => Ok(result_env),
+        Err(e) => {
+            for failure in hook_failures {
+                error!("{}", failure.red());
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Runs the steps of `block` once per combination of `matrix`, one after the other. Every
+/// combination starts from `env` with its values set as variables, the other changes it makes
+/// are kept once it is over.
+fn run_matrix<'a>(
+    matrix: &Matrix,
+    block: &Block,
+    block_name: &str,
+    config: &Config,
+    env: &Environment<'a>,
+) -> Result<Environment<'a>, RunnerError> {
+    let mut new_env = env.clone();
+    let mut outcomes = Vec::new();
+    let mut failure = None;
+    for combination in matrix.combinations() {
+        let label = combination_label(&combination);
+        if failure.is_some() {
+            outcomes.push((label, "skipped".dimmed()));
+            continue;
+        }
+        info!(
+            "{}",
+            format!("--- [Matrix: {block_name} ({label})] ---").magenta()
+        );
+        // The combination gets its own log so that its carried failures can be told apart.
+        let mut combination_env = env.clone();
+        combination_env.failures = FailureLog::default();
+        for (key, value) in &combination {
+            combination_env.upsert_variable(key.clone(), value.clone(), EnvVariableSource::Script);
+        }
+        let result = run_steps(block, block_name, config, &combination_env);
+        let carried = combination_env.failures.messages();
+        combination_env
+            .failures
+            .forward_to(&env.failures, &format!("[{label}] "));
+
+        match result {
+            Ok(mut result_env) => {
+                for (key, value) in &combination {
+                    if result_env.get_value(key) == Some(value.as_str()) {
+                        result_env.restore_variable(key, env);
                     }
+                }
+                new_env.merge_env(result_env.changed_since(env));
+                let outcome = if carried.is_empty() {
+                    "ok".green()
+                } else {
+                    "carried forward".yellow()
                 };
-                match result {
-                    Ok(new_env) => {
-                        env.merge_env(new_env.clone());
-                    }
-                    Err(e) => {
-                        if section_environment.execution_policy == ExecutionPolicy::CarryFroward {
-                            warn!(
-                                "{}",
-                                format!(
-                                    "Section '{section_name}' failed, carrying forward because global execution policy is CarryForward",
-                                )
-                                .to_string()
-                                .yellow()
-                            );
-                        } else {
-                            return Err(e);
-                        }
+                outcomes.push((label, outcome));
+            }
+            Err(e) => {
+                let msg = format!("Matrix combination {label} of '{block_name}' failed: {e}");
+                if !tolerate_failure(env, None, &msg) {
+                    failure = Some(e);
+                }
+                outcomes.push((label, "failed".red()));
+            }
+        }
+    }
+
+    info!("{}", format!("--- [Matrix: {block_name}] ---").magenta());
+    for (label, outcome) in outcomes {
+        info!("  {label}: {outcome}");
+    }
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(new_env),
+    }
+}
+
+/// Runs the steps of `block`, in a single shell session if it asks for one.
+fn run_steps<'a>(
+    block: &Block,
+    block_name: &str,
+    config: &Config,
+    env: &Environment<'a>,
+) -> Result<Environment<'a>, RunnerError> {
+    let Some(steps) = &block.steps else {
+        return Ok(env.clone());
+    };
+    if block.parallel.unwrap_or(false) {
+        return run_parallel(
+            steps,
+            block.max_parallel,
+            block.shared_env.as_deref(),
+            config,
+            env,
+            block_name,
+        );
+    }
+    if block.session.unwrap_or(env.session) {
+        if let Some(program) = env.resolved_shell().session_program(env.os) {
+            return run_session(steps, config, env, block_name, program);
+        }
+        warn!(
+            "{}",
+            format!("Session mode needs a POSIX shell, running '{block_name}' step by step")
+                .yellow()
+        );
+    }
+    run_tasks(steps, config, env, block_name)
+}
+
+/// Expands the references of the working directory of `step`, before it is joined to the
+/// inherited one.
+fn step_cwd(step: &StepSpec, step_env: &Environment) -> Result<Option<PathBuf>, String> {
+    let Some(cwd) = &step.cwd else {
+        return Ok(None);
+    };
+    interpolate(&cwd.to_string_lossy(), step_env)
+        .map(|cwd| Some(PathBuf::from(cwd)))
+        .map_err(|e| format!("cannot resolve the working directory, {e}"))
+}
+
+/// Builds the environment a structured step runs in from the one of its parent.
+pub(crate) fn step_environment<'a>(
+    step: &StepSpec,
+    env: &Environment<'a>,
+) -> Result<Environment<'a>, String> {
+    let mut step_env = env.clone();
+    if let Some(shell) = &step.shell {
+        step_env.shell = Some(shell.clone());
+    }
+    if let Some(env_vars) = &step.env {
+        apply_layer(&mut step_env, env_vars, EnvVariableSource::Local)
+            .map_err(|e| format!("cannot resolve the environment, {e}"))?;
+    }
+    if let Some(cwd) = step_cwd(step, &step_env)? {
+        let base = env.cwd.clone().unwrap_or_else(|| PathBuf::from("."));
+        step_env.cwd = Some(base.join(cwd));
+    }
+    Ok(step_env)
+}
+
+/// Name of `step` in the messages about its timeout and retries, its first line when it has
+/// none.
+fn step_label<'s>(step: &'s StepSpec, task: &'s str) -> &'s str {
+    match &step.name {
+        Some(name) => name,
+        None => task.lines().next().unwrap_or(task),
+    }
+}
+
+/// Starts the clock of the `timeout` of `step` for one of its attempts.
+fn attempt_environment<'a>(
+    step: &StepSpec,
+    task: &str,
+    step_env: &Environment<'a>,
+) -> Environment<'a> {
+    let mut attempt_env = step_env.clone();
+    if let Some(timeout) = step.timeout {
+        attempt_env.limit_time(step_label(step, task), timeout);
+    }
+    attempt_env
+}
+
+/// Step level variables must not leak into the following steps.
+fn unscope_step_env(
+    step: &StepSpec,
+    result_env: &mut Environment,
+    step_env: &Environment,
+    parent: &Environment,
+) {
+    for key in step.env.iter().flatten().map(|(key, _)| key) {
+        if result_env.get_value(key) == step_env.get_value(key) {
+            result_env.restore_variable(key, parent);
+        }
+    }
+}
+
+/// Reports a failure that the step or the execution policy allows to move past, returning
+/// `false` when it has to stop the parent instead. Failures carried forward by the policy are
+/// recorded for the final summary, explicitly allowed ones are not.
+fn tolerate_failure(env: &Environment, continue_on_error: Option<bool>, msg: &str) -> bool {
+    if stopped_by_interrupt(env) || out_of_time(env) {
+        false
+    } else if continue_on_error.unwrap_or(false) {
+        warn!("{}", msg.yellow());
+        true
+    } else if env.execution_policy == ExecutionPolicy::CarryFroward {
+        warn!("{}", msg.yellow());
+        env.failures.record(msg.to_string());
+        true
+    } else {
+        false
+    }
+}
+
+/// Whether Ctrl-C stops the steps running with `env`, which it does unless they are hooks.
+fn stopped_by_interrupt(env: &Environment) -> bool {
+    !env.uninterruptible && interrupt::interrupted()
+}
+
+/// Whether the time given to the steps running with `env` is up.
+fn out_of_time(env: &Environment) -> bool {
+    env.deadline
+        .as_ref()
+        .is_some_and(|deadline| deadline.expired())
+}
+
+/// Error stopping the steps running with `env` before the next one starts, if any.
+fn stop_before_step(env: &Environment) -> Option<RunnerError> {
+    if stopped_by_interrupt(env) {
+        return Some(RunnerError::Interrupted);
+    }
+    env.deadline
+        .as_ref()
+        .filter(|deadline| deadline.expired())
+        .map(|deadline| deadline.error())
+}
+
+/// Error reporting a command of `env` stopped by `stop`.
+fn stop_error(env: &Environment, stop: Stop) -> RunnerError {
+    match (stop, &env.deadline) {
+        (Stop::TimedOut, Some(deadline)) => deadline.error(),
+        _ => RunnerError::Interrupted,
+    }
+}
+
+/// What stops the commands running with `env` early.
+fn watch(env: &Environment) -> Watch {
+    Watch {
+        deadline: env.deadline.as_ref().map(|deadline| deadline.at),
+        interruptible: !env.uninterruptible,
+        grace: Duration::from_secs(env.timeout_grace.unwrap_or(DEFAULT_TIMEOUT_GRACE)),
+    }
+}
+
+/// Seconds a timed out command gets to exit before it is killed, unless `timeout_grace` says
+/// otherwise.
+const DEFAULT_TIMEOUT_GRACE: u64 = 5;
+
+/// Message of a step failure as reported when it is carried forward.
+fn failure_message(e: &RunnerError) -> String {
+    match e {
+        RunnerError::CmdFailed(msg) | RunnerError::StepFailed { message: msg, .. } => msg.clone(),
+        e => e.to_string(),
+    }
+}
+
+/// Runs `attempt` with `env`, then again as long as it fails and `retry` allows it, waiting
+/// for the backoff delay in between. Every attempt starts from `env` with a failure log of its
+/// own, only the environment and the carried failures of the last one are kept.
+fn with_retries<'a>(
+    retry: Retry,
+    label: &str,
+    env: &Environment<'a>,
+    mut attempt: impl FnMut(&Environment<'a>) -> Result<Environment<'a>, RunnerError>,
+) -> Result<Environment<'a>, RunnerError> {
+    if retry.retries == 0 {
+        return attempt(env);
+    }
+    let attempts = retry.retries + 1;
+    let mut number = 1;
+    loop {
+        info!(
+            "{}",
+            format!("Attempt {number}/{attempts} of '{label}'").dimmed()
+        );
+        let mut attempt_env = env.clone();
+        attempt_env.failures = FailureLog::default();
+        let result = attempt(&attempt_env);
+        let retried = match &result {
+            Ok(_) => {
+                if number > 1 {
+                    info!(
+                        "{}",
+                        format!("'{label}' succeeded on attempt {number}/{attempts}").green()
+                    );
+                }
+                false
+            }
+            Err(RunnerError::Interrupted) => false,
+            Err(e) => {
+                let exit_code = match e {
+                    RunnerError::StepFailed { exit_code, .. } => *exit_code,
+                    _ => None,
+                };
+                if number == attempts {
+                    warn!(
+                        "{}",
+                        format!("'{label}' failed {attempts} times, giving up").yellow()
+                    );
+                    false
+                } else if !retry.retries_exit_code(exit_code) {
+                    warn!(
+                        "{}",
+                        format!(
+                            "'{label}' failed with {}, which is not in `retry_on_exit_codes`",
+                            exit_code
+                                .map_or("no exit code".to_string(), |c| format!("exit code {c}"))
+                        )
+                        .yellow()
+                    );
+                    false
+                } else {
+                    let delay = retry.delay_before(number);
+                    let retrying = if delay.is_zero() {
+                        "retrying".to_string()
+                    } else {
+                        format!("retrying in {}s", delay.as_secs())
+                    };
+                    warn!(
+                        "{}",
+                        format!(
+                            "Attempt {number}/{attempts} of '{label}' failed, {retrying}: {}",
+                            failure_message(e)
+                        )
+                        .yellow()
+                    );
+                    wait_before_retry(env, delay)
+                }
+            }
+        };
+        if !retried {
+            attempt_env.failures.forward_to(&env.failures, "");
+            return result;
+        }
+        number += 1;
+    }
+}
+
+/// Waits `delay` before a retry, returning `false` when Ctrl-C or a timeout stops the steps
+/// running with `env` first.
+fn wait_before_retry(env: &Environment, delay: Duration) -> bool {
+    let until = Instant::now() + delay;
+    loop {
+        if stop_before_step(env).is_some() {
+            return false;
+        }
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return true;
+        }
+        thread::sleep(left.min(POLL_INTERVAL));
+    }
+}
+
+/// Remembers `step` as the last failed step for the hooks, unless it is expected to fail.
+fn record_failed_step(env: &Environment, step: &StepSpec, task: &str, exit_code: Option<i32>) {
+    if !step.continue_on_error.unwrap_or(false) {
+        env.failures.record_step(FailedStep {
+            name: step.name.clone().unwrap_or_else(|| task.to_string()),
+            exit_code,
+        });
+    }
+}
+
+fn log_step(step: &StepSpec, task: &str) {
+    match &step.name {
+        Some(name) => info!(
+            "{} {} {}",
+            "$".cyan(),
+            format!("[{name}]").cyan(),
+            task.cyan()
+        ),
+        None => info!("{} {}", "$".cyan(), task.cyan()),
+    }
+}
+
+/// Runs a single attempt of a step within its timeout, returning the resulting environment.
+fn run_step<'a>(
+    step: &StepSpec,
+    task: &str,
+    config: &Config,
+    env: &Environment<'a>,
+    parent_name: &str,
+) -> Result<Environment<'a>, RunnerError> {
+    let env = &attempt_environment(step, task, env);
+    if let Some(call) = block_call(config, step, task) {
+        let name = &call.name;
+        return run_block(name, &call.args, config, env).map_err(|e| {
+            let message = format!("Block '{name}' execution failed in parent '{parent_name}'");
+            match e {
+                RunnerError::TimedOut { .. } | RunnerError::Interrupted => e,
+                RunnerError::StepFailed { exit_code, .. } => {
+                    RunnerError::StepFailed { message, exit_code }
+                }
+                _ => RunnerError::CmdFailed(message),
+            }
+        });
+    }
+    match run_shell(task, env) {
+        Ok((status, result_env)) => {
+            if status.success() {
+                Ok(result_env)
+            } else {
+                record_failed_step(env, step, task, status.code());
+                Err(RunnerError::StepFailed {
+                    message: format!(
+                        "Parent '{}' command failed: '{}' (exit {:?})",
+                        parent_name,
+                        task,
+                        status.code()
+                    ),
+                    exit_code: status.code(),
+                })
+            }
+        }
+        Err(e) => {
+            record_failed_step(env, step, task, None);
+            Err(match e {
+                RunnerError::TimedOut { .. } | RunnerError::Interrupted => e,
+                e => RunnerError::CmdFailed(format!(
+                    "Parent '{parent_name}' command error: '{task}' -> {e}"
+                )),
+            })
+        }
+    }
+}
+
+pub fn run_tasks<'a>(
+    tasks: &[Step],
+    config: &Config,
+    env: &Environment<'a>,
+    parent_name: &str,
+) -> Result<Environment<'a>, RunnerError> {
+    let mut new_env = env.clone();
+
+    for step in tasks {
+        if let Some(e) = stop_before_step(env) {
+            return Err(e);
+        }
+        if let Step::Parallel(group) = step {
+            let outcome = run_group(group, config, &new_env, parent_name);
+            match outcome {
+                Ok(result_env) => new_env.merge_env(result_env),
+                Err(e) => {
+                    if !tolerate_failure(env, group.continue_on_error, &e.to_string()) {
+                        return Err(stop_before_step(env).unwrap_or(e));
                     }
                 }
             }
-            None => {
+            continue;
+        }
+
+        let step = step.spec();
+        let task = step.call.as_deref().unwrap_or(step.run.trim());
+        if task.is_empty() {
+            continue;
+        }
+
+        let step_env = match step_environment(&step, &new_env).and_then(|step_env| {
+            let holds = condition_holds(
+                step.condition.as_deref(),
+                &step_env,
+                step_label(&step, task),
+            )?;
+            Ok(holds.then_some(step_env))
+        }) {
+            Ok(Some(step_env)) => step_env,
+            Ok(None) => continue,
+            Err(e) => {
+                let msg = format!("Parent '{parent_name}' command error: '{task}' -> {e}");
+                if !tolerate_failure(env, step.continue_on_error, &msg) {
+                    return Err(RunnerError::CmdFailed(msg));
+                }
                 continue;
             }
         };
+        log_step(&step, task);
+        if env.dry_run {
+            continue;
+        }
+        let outcome = with_retries(
+            step.retry(),
+            step_label(&step, task),
+            &step_env,
+            |attempt_env| run_step(&step, task, config, attempt_env, parent_name),
+        );
+        match outcome {
+            Ok(mut result_env) => {
+                unscope_step_env(&step, &mut result_env, &step_env, &new_env);
+                new_env.merge_env(result_env);
+            }
+            Err(e) => {
+                if !tolerate_failure(env, step.continue_on_error, &failure_message(&e)) {
+                    return Err(e);
+                }
+            }
+        }
     }
-    Ok(())
+
+    Ok(new_env)
 }
 
-pub fn run_section<'a>(
-    section_name: &str,
+fn run_group<'a>(
+    group: &ParallelGroup,
     config: &Config,
-    tasks: &Vec<String>,
-    env: &'a Environment,
+    env: &Environment<'a>,
+    parent_name: &str,
 ) -> Result<Environment<'a>, RunnerError> {
-    info!(
-        "{}",
-        format!(
-            "----- [{}] -----",
-            Section::get_section(section_name).as_str()
-        )
-        .blue()
-    );
-    run_tasks(tasks, config, env, section_name)
+    let name = group.name.as_deref().unwrap_or(parent_name);
+    run_parallel(
+        &group.parallel,
+        group.max_parallel,
+        group.shared_env.as_deref(),
+        config,
+        env,
+        name,
+    )
 }
 
-pub fn run_block<'a>(
-    block_name: &str,
+/// Runs every step of `steps` concurrently, at most `max_parallel` at once.
+///
+/// Failures are reported once all the started steps are over, no new step being started
+/// after a failure under the fast fail policy. The variables changed by the steps are merged
+/// in declaration order, a variable changed by several steps being an error unless it is
+/// listed in `shared_env`.
+fn run_parallel<'a>(
+    steps: &[Step],
+    max_parallel: Option<usize>,
+    shared_env: Option<&[String]>,
     config: &Config,
-    env: &'a Environment,
+    env: &Environment<'a>,
+    parent_name: &str,
 ) -> Result<Environment<'a>, RunnerError> {
-    info!("{}", format!("--- [Block: {block_name}] ---").magenta());
-    let mut block_environment = env.clone();
-    if let Some(tasks) = config.blocks.get(block_name) {
-        match &tasks.steps {
-            Some(steps) => {
-                if config
-                    .blocks
-                    .get(block_name)
-                    .unwrap()
-                    .local_config
-                    .is_some()
-                {
-                    if let Some(env_vars) = &config
-                        .blocks
-                        .get(block_name)
-                        .unwrap()
-                        .local_config
-                        .as_ref()
-                        .unwrap()
-                        .env
+    info!(
+        "{}",
+        format!("--- [Parallel: {parent_name}, {} steps] ---", steps.len()).magenta()
+    );
+    let limit = max_parallel
+        .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1)
+        .max(1);
+    let mut results: Vec<Option<Result<Environment<'a>, RunnerError>>> =
+        (0..steps.len()).map(|_| None).collect();
+    let mut failed = false;
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        let mut next = 0;
+        let mut running = 0;
+        loop {
+            while next < steps.len() && running < limit && !failed {
+                let step = &steps[next];
+                let mut child_env = env.clone();
+                let label = step.label();
+                child_env.output_prefix = Some(match &env.output_prefix {
+                    Some(outer) => format!("{outer}] [{label}"),
+                    None => label,
+                });
+                let sender = sender.clone();
+                let index = next;
+                scope.spawn(move || {
+                    let result =
+                        run_tasks(std::slice::from_ref(step), config, &child_env, parent_name);
+                    let _ = sender.send((index, result));
+                });
+                next += 1;
+                running += 1;
+            }
+            if running == 0 {
+                break;
+            }
+            let Ok((index, result)) = receiver.recv() else {
+                break;
+            };
+            running -= 1;
+            failed |= result.is_err() && env.execution_policy != ExecutionPolicy::CarryFroward;
+            results[index] = Some(result);
+        }
+    });
+
+    let mut failures = Vec::new();
+    let mut worst: Option<RunnerError> = None;
+    let mut new_env = env.clone();
+    let mut changed_by: HashMap<String, String> = HashMap::new();
+    for (step, result) in steps.iter().zip(results) {
+        match result {
+            Some(Ok(result_env)) => {
+                let changes = result_env.changed_since(env);
+                for key in changes.get_variables().keys() {
+                    if SHELL_MANAGED_VARIABLES.contains(&key.as_str())
+                        || shared_env.is_some_and(|shared| shared.contains(key))
                     {
-                        for (key, value) in env_vars {
-                            block_environment.upsert_variable(
-                                key.to_string(),
-                                value.to_string(),
-                                EnvVariableSource::Local,
-                            );
-                        }
+                        continue;
                     }
-                    if let Some(exec_policy) = &config
-                        .blocks
-                        .get(block_name)
-                        .unwrap()
-                        .local_config
-                        .as_ref()
-                        .unwrap()
-                        .execution_policy
+                    if let Some(other) = changed_by.insert(key.clone(), step.label())
+                        && changes.get_value(key) != new_env.get_value(key)
                     {
-                        block_environment.execution_policy = exec_policy.clone();
+                        failures.push(format!(
+                            "Variable '{key}' is changed by both '{other}' and '{}', list it in `shared_env` to allow it",
+                            step.label()
+                        ));
                     }
                 }
+                new_env.merge_env(changes);
+            }
+            Some(Err(e)) => {
+                failures.push(e.to_string());
+                if worst
+                    .as_ref()
+                    .is_none_or(|worst| severity(&e) > severity(worst))
+                {
+                    worst = Some(e);
+                }
+            }
+            None => {}
+        }
+    }
+
+    if failures.is_empty() {
+        return Ok(new_env);
+    }
+    let message = format!(
+        "Parallel steps failed in parent '{parent_name}': {}",
+        failures.join("; ")
+    );
+    // Keep what ended the most severe step, so that its exit code is the one reported and
+    // matched by `retry_on_exit_codes`.
+    Err(match worst {
+        Some(e @ (RunnerError::Interrupted | RunnerError::TimedOut { .. })) => {
+            error!("{message}");
+            e
+        }
+        Some(RunnerError::StepFailed { exit_code, .. }) => {
+            RunnerError::StepFailed { message, exit_code }
+        }
+        _ => RunnerError::CmdFailed(message),
+    })
+}
 
-                let current_environment = block_environment.clone();
+/// Rank of a step failure, an interruption outranking a timeout outranking a failed command.
+fn severity(error: &RunnerError) -> u8 {
+    match error {
+        RunnerError::Interrupted => 3,
+        RunnerError::TimedOut { .. } => 2,
+        RunnerError::StepFailed { .. } => 1,
+        _ => 0,
+    }
+}
 
-                let res = run_tasks(steps, config, &current_environment, block_name);
-                let out: Result<Environment, RunnerError> = match res {
-                    Ok(new_env) => {
-                        block_environment.merge_env(new_env);
-                        return Ok(block_environment);
-                    }
-                    Err(e) => Err(e),
-                };
+/// Variables every shell maintains on its own, they are never pushed into a session.
+static SHELL_MANAGED_VARIABLES: &[&str] = &["PWD", "OLDPWD", "SHLVL", "_"];
 
-                if out.is_err() {
-                    if env.execution_policy == ExecutionPolicy::CarryFroward {
-                        let internal_error = out.err().unwrap().to_string().yellow();
-                        warn!("{internal_error}");
-                        warn!("{}", format!("Block '{block_name}' failed silently, moving forward because the parent execution policy is CarryForward").yellow());
-                        Ok(block_environment)
-                    } else {
-                        let internal_error = out.as_ref().err().unwrap().to_string().red();
-                        error!("{internal_error}");
-                        out
-                    }
-                } else {
-                    out
-                }
-            }
-            None => Ok(block_environment),
+/// Wraps a structured step so that its own `cwd` and `env`, as resolved in `step_env`, only
+/// apply to a subshell of the session.
+fn session_script(task: &str, step: &StepSpec, step_env: &Environment) -> Result<String, String> {
+    if step.cwd.is_none() && step.env.is_none() {
+        return Ok(task.to_string());
+    }
+    let mut script = String::from("(\n");
+    if let Some(cwd) = step_cwd(step, step_env)? {
+        script += &format!("cd {} || exit\n", quote_posix(&cwd.to_string_lossy()));
+    }
+    let mut keys: Vec<&String> = step.env.iter().flatten().map(|(key, _)| key).collect();
+    keys.sort();
+    for key in keys {
+        match step_env.get_value(key) {
+            Some(value) => script += &format!("export {key}={}\n", quote_posix(value)),
+            None => script += &format!("unset {key}\n"),
         }
-    } else {
-        Err(RunnerError::CmdFailed(format!(
-            "Block '{block_name}' not found"
-        )))
     }
+    Ok(script + task + "\n)")
 }
 
-pub fn run_tasks<'a>(
-    tasks: &Vec<String>,
+/// Loads the current environment of the session into `env`.
+fn sync_from_session(session: &mut Session, env: &mut Environment) -> Result<(), RunnerError> {
+    if let Some(dump) = session.snapshot()? {
+        env.load_script_env(&dump);
+    }
+    Ok(())
+}
+
+/// Pushes the variables changed outside of the session into it.
+fn sync_to_session(
+    session: &mut Session,
+    result_env: &Environment,
+    env: &Environment,
+) -> Result<(), RunnerError> {
+    let mut sync = String::new();
+    for (key, value) in result_env.changes_since(env) {
+        if SHELL_MANAGED_VARIABLES.contains(&key.as_str()) {
+            continue;
+        }
+        match value {
+            Some(value) => sync += &format!("export {key}={}\n", quote_posix(&value)),
+            None => sync += &format!("unset {key}\n"),
+        }
+    }
+    if !sync.is_empty() {
+        session.run(&sync, Watch::default())?;
+    }
+    Ok(())
+}
+
+/// Runs `tasks` in one long lived shell. Steps needing another shell and block calls still run
+/// in their own processes, the session environment being synced before and after them.
+fn run_session<'a>(
+    tasks: &[Step],
     config: &Config,
-    env: &'a Environment,
+    env: &Environment<'a>,
     parent_name: &str,
+    program: &str,
 ) -> Result<Environment<'a>, RunnerError> {
-    let order = tasks;
+    let capture = Capture::new()?;
+    let mut cmd = session_command(program, &capture);
+    apply_environment(&mut cmd, env);
+    let mut session = Session::start(cmd, capture, env.output_prefix.clone())?;
     let mut new_env = env.clone();
 
-    for task in order {
-        info!("{} {}", "$".cyan(), task.cyan());
+    for (index, step) in tasks.iter().enumerate() {
+        if let Some(e) = stop_before_step(env) {
+            return Err(e);
+        }
+        if let Step::Parallel(group) = step {
+            sync_from_session(&mut session, &mut new_env)?;
+            match run_group(group, config, &new_env, parent_name) {
+                Ok(result_env) => {
+                    sync_to_session(&mut session, &result_env, &new_env)?;
+                    new_env.merge_env(result_env);
+                }
+                Err(e) => {
+                    if !tolerate_failure(env, group.continue_on_error, &e.to_string()) {
+                        return Err(stop_before_step(env).unwrap_or(e));
+                    }
+                }
+            }
+            continue;
+        }
 
-        if env.dry_run || task.trim().is_empty() {
+        let step = step.spec();
+        let task = step.call.as_deref().unwrap_or(step.run.trim());
+        if task.is_empty() {
             continue;
         }
 
-        let task = task.trim();
-        let is_block = task.split(' ').count() == 1
-            && !task.starts_with('\'')
-            && !task.starts_with('"')
-            && config.blocks.contains_key(task);
-        if is_block {
-            let block_name = task.trim();
-            if config.blocks.contains_key(block_name) {
-                let current_environment = new_env.clone();
-                match run_block(block_name, config, &current_environment) {
-                    Ok(result_env) => {
-                        new_env.merge_env(result_env);
-                    }
-                    Err(_) => {
-                        let msg = format!(
-                            "Block '{block_name}' execution failed in parent '{parent_name}'"
-                        );
-                        if env.execution_policy == ExecutionPolicy::CarryFroward {
-                            warn!("{}", msg.yellow());
-                            // failures.push(msg);
-                        } else {
-                            return Err(RunnerError::CmdFailed(msg));
-                        }
-                    }
+        let step_error =
+            |e: String| format!("Parent '{parent_name}' command error: '{task}' -> {e}");
+        // The settings of the step may refer to variables exported earlier in the session.
+        if step.env.is_some() || step.cwd.is_some() || step.condition.is_some() {
+            sync_from_session(&mut session, &mut new_env)?;
+        }
+        let step_env = match step_environment(&step, &new_env).and_then(|step_env| {
+            let holds = condition_holds(
+                step.condition.as_deref(),
+                &step_env,
+                step_label(&step, task),
+            )?;
+            Ok(holds.then_some(step_env))
+        }) {
+            Ok(Some(step_env)) => step_env,
+            Ok(None) => continue,
+            Err(e) => {
+                let msg = step_error(e);
+                if !tolerate_failure(env, step.continue_on_error, &msg) {
+                    return Err(RunnerError::CmdFailed(msg));
                 }
+                continue;
+            }
+        };
+        log_step(&step, task);
+        if env.dry_run {
+            continue;
+        }
+        // Retried steps run in processes of their own, so that the attempts failing before
+        // the last one leave nothing behind in the session.
+        let outcome = if step.shell.is_some()
+            || step.retry().retries > 0
+            || block_call(config, &step, task).is_some()
+        {
+            sync_from_session(&mut session, &mut new_env)?;
+            let outcome = step_environment(&step, &new_env)
+                .map_err(|e| RunnerError::CmdFailed(step_error(e)))
+                .and_then(|step_env| {
+                    let mut result_env = with_retries(
+                        step.retry(),
+                        step_label(&step, task),
+                        &step_env,
+                        |attempt_env| run_step(&step, task, config, attempt_env, parent_name),
+                    )?;
+                    unscope_step_env(&step, &mut result_env, &step_env, &new_env);
+                    Ok(result_env)
+                });
+            if let Ok(result_env) = &outcome {
+                sync_to_session(&mut session, result_env, &new_env)?;
             }
+            outcome
         } else {
-            let current_environment = new_env.clone();
-            let res: Result<(ExitStatus, Environment), RunnerError> =
-                run_shell(task, &current_environment);
-            match res {
-                Ok((status, result_env)) => {
-                    if status.success() {
-                        new_env.merge_env(result_env);
-                    } else {
-                        let msg = format!(
-                            "Parent '{}' command failed: '{}' (exit {:?})",
-                            parent_name,
-                            task,
-                            status.code()
-                        );
-                        if env.execution_policy == ExecutionPolicy::CarryFroward {
-                            warn!("{}", msg.yellow());
-                            // failures.push(msg);
-                        } else {
-                            return Err(RunnerError::CmdFailed(msg));
+            match session_script(task, &step, &step_env) {
+                Err(e) => Err(RunnerError::CmdFailed(step_error(e))),
+                Ok(script) => {
+                    let attempt_env = attempt_environment(&step, task, &step_env);
+                    match session.run(&script, watch(&attempt_env))? {
+                        StepOutcome::Exited(0) => Ok(new_env.clone()),
+                        StepOutcome::Exited(code) => {
+                            record_failed_step(env, &step, task, Some(code));
+                            Err(RunnerError::StepFailed {
+                                message: format!(
+                                    "Parent '{parent_name}' command failed: '{task}' (exit Some({code}))"
+                                ),
+                                exit_code: Some(code),
+                            })
+                        }
+                        StepOutcome::ShellExited => {
+                            // The session went away with the step, the remaining ones cannot
+                            // run, whether or not the step failed.
+                            let (status, dump) = session.finish()?;
+                            if let Some(dump) = dump {
+                                new_env.load_script_env(&dump);
+                            }
+                            if !status.success() {
+                                record_failed_step(env, &step, task, status.code());
+                                let msg = format!(
+                                    "Parent '{parent_name}' session shell exited during '{task}' (exit {:?})",
+                                    status.code()
+                                );
+                                if !tolerate_failure(env, step.continue_on_error, &msg) {
+                                    return Err(RunnerError::StepFailed {
+                                        message: msg,
+                                        exit_code: status.code(),
+                                    });
+                                }
+                            }
+                            if index + 1 < tasks.len() {
+                                warn!(
+                                    "{}",
+                                    format!(
+                                        "The session of '{parent_name}' ended with '{task}', its remaining steps are not run"
+                                    )
+                                    .yellow()
+                                );
+                            }
+                            return Ok(new_env);
+                        }
+                        StepOutcome::Stopped(stop) => {
+                            // The session went down with the step, the remaining ones cannot run.
+                            record_failed_step(env, &step, task, None);
+                            return Err(stop_error(&attempt_env, stop));
                         }
                     }
                 }
-                Err(e) => {
-                    let msg =
-                        format!("Parent '{parent_name}' command spawn error: '{task}' -> {e}");
-                    if env.execution_policy == ExecutionPolicy::CarryFroward {
-                        warn!("{}", msg.yellow());
-                        // failures.push(msg);
-                    } else {
-                        return Err(RunnerError::CmdFailed(msg));
-                    }
+            }
+        };
+
+        match outcome {
+            Ok(result_env) => new_env.merge_env(result_env),
+            Err(e) => {
+                if !tolerate_failure(env, step.continue_on_error, &failure_message(&e)) {
+                    return Err(e);
                 }
             }
         }
     }
 
+    let (_, dump) = session.finish()?;
+    if let Some(dump) = dump {
+        new_env.load_script_env(&dump);
+    }
     Ok(new_env)
 }
 
@@ -392,43 +1367,28 @@ fn msys_path_to_windows(msys_path: &str) -> String {
     let parts: Vec<String> = msys_path
         .split(':')
         .map(|p| {
-            if p.len() >= 3 && p.as_bytes()[0] == b'/' && p.as_bytes()[2] == b'/' {
-                let drive = p.chars().nth(1).unwrap().to_ascii_uppercase();
-                let rest = &p[3..];
-                let back = rest.replace('/', r"\");
-                format!("{drive}:\\{back}")
-            } else {
-                p.to_string()
+            let mut chars = p.chars();
+            match (chars.next(), chars.next(), chars.next()) {
+                (Some('/'), Some(drive), Some('/')) if drive.is_ascii_alphabetic() => {
+                    let back = chars.as_str().replace('/', r"\");
+                    format!("{}:\\{back}", drive.to_ascii_uppercase())
+                }
+                _ => p.to_string(),
             }
         })
         .collect();
     parts.join(";")
 }
 
-fn run_shell<'a>(
-    cmdline: &str,
-    env: &'a Environment,
-) -> Result<(ExitStatus, Environment<'a>), RunnerError> {
-    let mut cmd = if env.os == "windows" {
-        let mut c = Command::new("cmd");
-        c.arg("/C")
-            .arg(cmdline.to_string() + "&& set > .env.vars.zbuild");
-        c.env("TERM", "xterm-256color");
-        c.env("ANSICON", "1");
-        c
-    } else {
-        let mut c = Command::new("sh");
-        c.arg("-c")
-            .arg(cmdline.to_string() + "&& env > .env.vars.zbuild");
-        c.env("TERM", "xterm-256color");
-        c
-    };
-
+/// Passes the working directory and variables of `env` on to `cmd`.
+fn apply_environment(cmd: &mut Command, env: &Environment) {
     if let Some(ref dir) = env.cwd {
         cmd.current_dir(dir);
     }
     for (k, v) in env.get_variables() {
-        if env.os == "windows" && k == "PATH" {
+        if v.removed {
+            cmd.env_remove(k);
+        } else if env.os == "windows" && k == "PATH" {
             let path_for_cmd = if v.value.contains("/c/") || v.value.contains("/C/") {
                 msys_path_to_windows(&v.value)
             } else {
@@ -439,34 +1399,154 @@ fn run_shell<'a>(
             cmd.env(k, v.value.clone());
         }
     }
+}
+
+fn run_shell<'a>(
+    cmdline: &str,
+    env: &Environment<'a>,
+) -> Result<(ExitStatus, Environment<'a>), RunnerError> {
+    let mut capture = Capture::new()?;
+    let mut cmd = shell_command(cmdline, &env.resolved_shell(), env.os, &mut capture)?;
 
+    apply_environment(&mut cmd, env);
+
+    let output = || match env.output_prefix {
+        Some(_) => Stdio::piped(),
+        None => Stdio::inherit(),
+    };
     let mut child = cmd
         .stdin(Stdio::null())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
+        .stdout(output())
+        .stderr(output())
         .spawn()?;
 
-    let status = child.wait()?;
+    let mut forwarders = Vec::new();
+    if let Some(prefix) = &env.output_prefix {
+        if let Some(stdout) = child.stdout.take() {
+            let prefix = prefix.clone();
+            forwarders.push(thread::spawn(move || {
+                forward_prefixed(stdout, &prefix, false)
+            }));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            let prefix = prefix.clone();
+            forwarders.push(thread::spawn(move || {
+                forward_prefixed(stderr, &prefix, true)
+            }));
+        }
+    }
 
-    // Read .env.vars from previous command if exists
-    let env_vars_path = if let Some(ref dir) = env.cwd {
-        dir.join(".env.vars.zbuild")
-    } else {
-        PathBuf::from(".env.vars.zbuild")
-    };
+    let status = process_group::wait(&mut child, watch(env))?;
+    for forwarder in forwarders {
+        let _ = forwarder.join();
+    }
+    let status = status.map_err(|stop| stop_error(env, stop))?;
 
     let mut new_environment = env.clone();
+    if let Some(content) = capture.read() {
+        new_environment.load_script_env(&content);
+    }
 
-    if env_vars_path.exists()
-        && let Ok(content) = std::fs::read_to_string(&env_vars_path)
-    {
-        new_environment.load_env(content, EnvVariableSource::Script);
+    Ok((status, new_environment))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn commands(commands: &[&str]) -> Vec<Step> {
+        commands
+            .iter()
+            .map(|command| serde_yaml::from_str(command).unwrap())
+            .collect()
     }
 
-    // Clean up .env.vars after reading
-    if env_vars_path.exists() {
-        let _ = std::fs::remove_file(&env_vars_path);
+    fn run(
+        steps: &[&str],
+        shared_env: Option<&[String]>,
+    ) -> Result<Environment<'static>, RunnerError> {
+        let config = Config::default();
+        run_parallel(
+            &commands(steps),
+            Some(steps.len()),
+            shared_env,
+            &config,
+            &Environment::default(),
+            "group",
+        )
     }
 
-    Ok((status, new_environment))
+    #[test]
+    fn variables_changed_by_several_steps_conflict() {
+        let error = run(&["export A=1", "export A=2"], None).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Variable 'A' is changed by both 'export A=1' and 'export A=2'"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn shared_variables_take_the_value_of_the_last_step() {
+        let shared = ["A".to_string()];
+        let env = run(&["export A=1", "export A=2"], Some(&shared)).unwrap();
+        assert_eq!(env.get_value("A"), Some("2"));
+    }
+
+    #[test]
+    fn identical_changes_and_distinct_variables_merge() {
+        let env = run(&["export A=1 B=2", "export A=1 C=3"], None).unwrap();
+        assert_eq!(env.get_value("A"), Some("1"));
+        assert_eq!(env.get_value("B"), Some("2"));
+        assert_eq!(env.get_value("C"), Some("3"));
+    }
+
+    #[test]
+    fn the_most_severe_step_failure_is_kept() {
+        let error = run(&["exit 3", "sh -c 'exit 4'"], None).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                RunnerError::StepFailed {
+                    exit_code: Some(3 | 4),
+                    ..
+                }
+            ),
+            "{error:?}"
+        );
+
+        let error = run(&["sleep 2; exit 3", "{run: sleep 5, timeout: 1}"], None).unwrap_err();
+        assert!(matches!(error, RunnerError::TimedOut { .. }), "{error:?}");
+    }
+
+    #[test]
+    fn steps_ending_the_session_stop_the_block() {
+        let config = Config::default();
+        let session = |steps: &[&str]| {
+            run_session(
+                &commands(steps),
+                &config,
+                &Environment::default(),
+                "block",
+                "sh",
+            )
+        };
+        let env = session(&["export A=1", "exit 0", "export A=2"]).unwrap();
+        assert_eq!(env.get_value("A"), Some("1"));
+
+        let error = session(&["exit 3", "true"]).unwrap_err();
+        assert!(
+            matches!(
+                error,
+                RunnerError::StepFailed {
+                    exit_code: Some(3),
+                    ..
+                }
+            ),
+            "{error:?}"
+        );
+
+        assert!(session(&["{run: exit 3, continue_on_error: true}"]).is_ok());
+    }
 }
@@ -0,0 +1,230 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    config_model::{Config, PlatformCommands, SectionId},
+    environment::Environment,
+};
+
+/// Section only run when explicitly requested with `--section`.
+pub(crate) const CLEAN_SECTION: &str = "clean";
+
+/// A section selected for this run, together with the sections it has to wait for.
+pub struct ScheduledSection<'c> {
+    pub name: &'c str,
+    pub commands: Option<&'c PlatformCommands>,
+    /// Indices into the schedule, always lower than the index of this section.
+    pub needs: Vec<usize>,
+}
+
+fn explicit_needs<'c>(config: &'c Config, name: &str) -> Option<&'c Vec<String>> {
+    config.tasks.0.get(name).and_then(|c| c.needs.as_ref())
+}
+
+/// Returns the first dependency cycle between sections, as a path starting and ending with
+/// the same section.
+pub fn find_cycle(config: &Config) -> Option<Vec<String>> {
+    fn visit<'c>(
+        config: &'c Config,
+        name: &'c str,
+        path: &mut Vec<&'c str>,
+        finished: &mut BTreeSet<&'c str>,
+    ) -> Option<Vec<String>> {
+        if let Some(start) = path.iter().position(|n| *n == name) {
+            let mut cycle: Vec<String> = path[start..].iter().map(|n| n.to_string()).collect();
+            cycle.push(name.to_string());
+            return Some(cycle);
+        }
+        if finished.contains(name) {
+            return None;
+        }
+        path.push(name);
+        for dependency in explicit_needs(config, name).into_iter().flatten() {
+            if let Some(cycle) = visit(config, dependency, path, finished) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        finished.insert(name);
+        None
+    }
+
+    let mut finished = BTreeSet::new();
+    config
+        .sections()
+        .names()
+        .iter()
+        .find_map(|name| visit(config, name, &mut Vec::new(), &mut finished))
+}
+
+/// Selects the sections to run and orders them so that every section comes after the ones it
+/// needs, keeping the pipeline order otherwise.
+///
+/// A section declaring `needs` only waits for those sections, a section without it waits for
+/// every section scheduled before it. Explicitly requested sections pull in what they need
+/// unless `no_deps` is set.
+pub fn schedule<'c>(config: &'c Config, env: &Environment) -> Vec<ScheduledSection<'c>> {
+    let sections = config.sections();
+    let clean = sections.id(CLEAN_SECTION);
+    let mut selected: Vec<bool> = match &env.sections {
+        Some(requested) => sections.ids().map(|id| requested.contains(&id)).collect(),
+        None => sections
+            .ids()
+            .map(|id| {
+                Some(id) != clean
+                    && !env
+                        .banned_sections
+                        .as_ref()
+                        .is_some_and(|banned| banned.contains(&id))
+            })
+            .collect(),
+    };
+    let needs_of = |id: SectionId| -> Vec<SectionId> {
+        explicit_needs(config, sections.name(id))
+            .into_iter()
+            .flatten()
+            .filter_map(|dependency| sections.id(dependency))
+            .collect()
+    };
+
+    if env.sections.is_some() && !env.no_deps {
+        let mut pending: Vec<SectionId> =
+            sections.ids().filter(|id| selected[id.index()]).collect();
+        while let Some(id) = pending.pop() {
+            for dependency in needs_of(id) {
+                if !selected[dependency.index()] {
+                    selected[dependency.index()] = true;
+                    pending.push(dependency);
+                }
+            }
+        }
+    }
+
+    // Kahn's algorithm over the explicit needs, preferring the pipeline order.
+    let mut order: Vec<SectionId> = Vec::new();
+    let mut placed = vec![false; sections.len()];
+    while let Some(next) = sections.ids().find(|&id| {
+        selected[id.index()]
+            && !placed[id.index()]
+            && needs_of(id)
+                .iter()
+                .all(|dependency| !selected[dependency.index()] || placed[dependency.index()])
+    }) {
+        placed[next.index()] = true;
+        order.push(next);
+    }
+
+    order
+        .iter()
+        .enumerate()
+        .map(|(slot, &id)| {
+            let name = sections.name(id);
+            let needs = match explicit_needs(config, name) {
+                Some(_) => {
+                    let needs = needs_of(id);
+                    order[..slot]
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, earlier)| needs.contains(earlier))
+                        .map(|(earlier_slot, _)| earlier_slot)
+                        .collect()
+                }
+                None => (0..slot).collect(),
+            };
+            ScheduledSection {
+                name,
+                commands: config.tasks.0.get(name),
+                needs,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_config_yaml;
+
+    fn config(yaml: &str) -> Config {
+        parse_config_yaml(yaml).unwrap()
+    }
+
+    /// Scheduled sections with the names of the sections they wait for.
+    fn scheduled(config: &Config, env: &Environment) -> Vec<(String, Vec<String>)> {
+        let schedule = schedule(config, env);
+        schedule
+            .iter()
+            .map(|section| {
+                let needs = section
+                    .needs
+                    .iter()
+                    .map(|index| schedule[*index].name.to_string())
+                    .collect();
+                (section.name.to_string(), needs)
+            })
+            .collect()
+    }
+
+    fn section(name: &str, needs: &[&str]) -> (String, Vec<String>) {
+        (
+            name.to_string(),
+            needs.iter().map(|need| need.to_string()).collect(),
+        )
+    }
+
+    const PIPELINE: &str = "pipeline: [build, lint, test, clean]\ntasks:\n  build:\n    linux:\n      steps: [make]\n  lint:\n    needs: []\n    linux:\n      steps: [lint]\n  test:\n    needs: [build]\n    linux:\n      steps: [make test]\n  clean:\n    linux:\n      steps: [rm -rf out]\n";
+
+    #[test]
+    fn sections_wait_for_their_needs_or_every_earlier_section() {
+        let config = config(PIPELINE);
+        assert_eq!(
+            scheduled(&config, &Environment::default()),
+            [
+                section("build", &[]),
+                section("lint", &[]),
+                section("test", &["build"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn needs_reorder_the_pipeline() {
+        let config = config(
+            "pipeline: [test, build, docs]\ntasks:\n  test:\n    needs: [build]\n    linux:\n      steps: [make test]\n",
+        );
+        assert_eq!(
+            scheduled(&config, &Environment::default()),
+            [
+                section("build", &[]),
+                section("test", &["build"]),
+                section("docs", &["build", "test"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn requested_sections_pull_in_their_needs() {
+        let config = config(PIPELINE);
+        let sections = config.sections();
+        let mut env = Environment::default();
+        env.sections = Some(vec![sections.id("test").unwrap()]);
+        assert_eq!(
+            scheduled(&config, &env),
+            [section("build", &[]), section("test", &["build"])]
+        );
+
+        env.no_deps = true;
+        assert_eq!(scheduled(&config, &env), [section("test", &[])]);
+
+        env.sections = Some(vec![sections.id("clean").unwrap()]);
+        assert_eq!(scheduled(&config, &env), [section("clean", &[])]);
+    }
+
+    #[test]
+    fn needs_cycles_are_found() {
+        assert_eq!(find_cycle(&config(PIPELINE)), None);
+        let config = config(
+            "pipeline: [a, b, c]\ntasks:\n  a:\n    needs: [c]\n  b:\n    needs: [a]\n  c:\n    needs: [b]\n",
+        );
+        assert_eq!(find_cycle(&config).unwrap(), ["a", "c", "b", "a"]);
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    process::{self, Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    },
+    thread,
+};
+
+use crate::{
+    process_group::{self, POLL_INTERVAL, Stop, Watch},
+    shell::{Capture, env_dump_command, forward_prefixed},
+};
+
+static SESSION_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+enum Event {
+    Done(i32),
+    Closed,
+}
+
+/// How a single step sent to a [`Session`] ended.
+pub(crate) enum StepOutcome {
+    Exited(i32),
+    /// The shell itself went away, e.g. because of `exit` or `set -e`.
+    ShellExited,
+    /// The step was stopped, along with the whole session.
+    Stopped(Stop),
+}
+
+/// A long lived shell running every step of a block, so that `cd`, functions, aliases and
+/// shell options survive from one step to the next.
+///
+/// Steps are written to the shell's stdin, each followed by a sentinel line carrying its exit
+/// code which is filtered out of the forwarded output.
+pub(crate) struct Session {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    events: Receiver<Event>,
+    sentinel: String,
+    capture: Capture,
+}
+
+impl Session {
+    /// Starts `cmd`, which must be a POSIX shell reading its script from stdin. Output lines are
+    /// prefixed with `output_prefix` when one is given.
+    pub fn start(
+        mut cmd: Command,
+        capture: Capture,
+        output_prefix: Option<String>,
+    ) -> io::Result<Self> {
+        let sentinel = format!(
+            "__zbuild_step_done_{}_{}__",
+            process::id(),
+            SESSION_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(if output_prefix.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::inherit()
+            })
+            .spawn()?;
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            let _ = child.kill();
+            return Err(io::Error::other("session shell has no stdio pipes"));
+        };
+
+        if let (Some(stderr), Some(prefix)) = (child.stderr.take(), output_prefix.clone()) {
+            thread::spawn(move || forward_prefixed(stderr, &prefix, true));
+        }
+        let (sender, events) = mpsc::channel();
+        let marker = sentinel.clone();
+        thread::spawn(move || forward_output(stdout, &marker, output_prefix.as_deref(), sender));
+
+        let mut session = Self {
+            child,
+            stdin: Some(stdin),
+            events,
+            sentinel,
+            capture,
+        };
+        // The environment is dumped whenever the shell goes away, even through `exit`.
+        session.write(&format!("trap '{}' EXIT\n", env_dump_command(false)))?;
+        Ok(session)
+    }
+
+    fn write(&mut self, script: &str) -> io::Result<()> {
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::other("session is closed"))?;
+        stdin.write_all(script.as_bytes())?;
+        stdin.flush()
+    }
+
+    /// Runs `script` in the session and waits for it to finish, stopping the whole session
+    /// when `watch` asks for it.
+    pub fn run(&mut self, script: &str, watch: Watch) -> io::Result<StepOutcome> {
+        let framed = format!(
+            "{{ {script}\n}} </dev/null\nprintf '%s%d\\n' '{}' \"$?\"\n",
+            self.sentinel
+        );
+        if self.write(&framed).is_err() {
+            return Ok(StepOutcome::ShellExited);
+        }
+
+        let event = loop {
+            let event = if watch.deadline.is_none() && !watch.interruptible {
+                self.events
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                self.events.recv_timeout(POLL_INTERVAL)
+            };
+            match event {
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some(stop) = watch.check() {
+                        process_group::terminate(&mut self.child, stop, watch.grace);
+                        self.stdin = None;
+                        return Ok(StepOutcome::Stopped(stop));
+                    }
+                }
+                event => break event,
+            }
+        };
+        match event {
+            Ok(Event::Done(code)) => Ok(StepOutcome::Exited(code)),
+            Ok(Event::Closed) | Err(_) => Ok(StepOutcome::ShellExited),
+        }
+    }
+
+    /// Dumps the current environment of the shell without ending the session.
+    pub fn snapshot(&mut self) -> io::Result<Option<String>> {
+        match self.run(&env_dump_command(false), Watch::default())? {
+            StepOutcome::Exited(0) => Ok(self.capture.read()),
+            _ => Ok(None),
+        }
+    }
+
+    /// Closes the session, returning the exit status of the shell and its final environment.
+    pub fn finish(mut self) -> io::Result<(ExitStatus, Option<String>)> {
+        self.stdin = None;
+        let status = self.child.wait()?;
+        Ok((status, self.capture.read()))
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if self.stdin.take().is_some() {
+            process_group::kill(&mut self.child);
+        }
+    }
+}
+
+/// Forwards the shell's stdout to ours, turning sentinel lines into events.
+fn forward_output(
+    stdout: ChildStdout,
+    sentinel: &str,
+    prefix: Option<&str>,
+    events: Sender<Event>,
+) {
+    let mut reader = BufReader::new(stdout);
+    let mut out = io::stdout();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => {
+                let _ = events.send(Event::Closed);
+                return;
+            }
+            Ok(_) => {}
+        }
+        let text = String::from_utf8_lossy(&line);
+        let (output, code) = match text.find(sentinel) {
+            // Output without a trailing newline ends up in front of the sentinel.
+            Some(pos) => (
+                &text[..pos],
+                Some(text[pos + sentinel.len()..].trim().parse().unwrap_or(-1)),
+            ),
+            None => (text.as_ref(), None),
+        };
+        if !output.is_empty() {
+            let output = output.trim_end_matches(['\n', '\r']);
+            let _ = match prefix {
+                Some(prefix) => writeln!(out, "[{prefix}] {output}"),
+                None => writeln!(out, "{output}"),
+            };
+        }
+        if let Some(code) = code {
+            let _ = events.send(Event::Done(code));
+        }
+        let _ = out.flush();
+    }
+}
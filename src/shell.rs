@@ -0,0 +1,365 @@
+use crate::{
+    config_model::{CaptureStrategy, Shell, ShellProfile},
+    process_group::own_process_group,
+};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::{self, Command},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Variable through which a child process learns where to write its environment dump.
+pub(crate) const CAPTURE_VARIABLE: &str = "ZBUILD_ENV_FILE";
+
+/// Placeholder of a custom shell `argv` replaced by the script to run.
+const SCRIPT_PLACEHOLDER: &str = "{script}";
+
+/// Helper variables of the capture wrappers, never recorded as part of an environment.
+pub(crate) static INTERNAL_VARIABLES: &[&str] = &[CAPTURE_VARIABLE, "ZBUILD_EXIT_CODE"];
+
+static CAPTURE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Temporary files backing a single command invocation, they live outside of the working
+/// tree and are removed once dropped.
+pub(crate) struct Capture {
+    dump: PathBuf,
+    scripts: Vec<PathBuf>,
+}
+
+impl Capture {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            dump: unique_temp_file("env")?,
+            scripts: Vec::new(),
+        })
+    }
+
+    /// Reads the environment dump written by the child, if it wrote one.
+    pub fn read(&self) -> Option<String> {
+        fs::read(&self.dump)
+            .ok()
+            .filter(|content| !content.is_empty())
+            .map(|content| String::from_utf8_lossy(&content).into_owned())
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.dump);
+        for script in &self.scripts {
+            let _ = fs::remove_file(script);
+        }
+    }
+}
+
+fn unique_temp_file(extension: &str) -> io::Result<PathBuf> {
+    loop {
+        let path = std::env::temp_dir().join(format!(
+            "zbuild-{}-{}.{extension}",
+            process::id(),
+            CAPTURE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        // A crashed run with a recycled pid may have left the file behind, never reuse it.
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(path),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Command writing the environment as NUL separated `KEY=VALUE` entries to the capture file,
+/// so that values containing newlines survive the round trip.
+pub(crate) fn env_dump_command(windows: bool) -> String {
+    if windows {
+        format!(
+            "powershell -NoProfile -NonInteractive -Command \"[IO.File]::WriteAllText($env:{CAPTURE_VARIABLE}, -join ([Environment]::GetEnvironmentVariables().GetEnumerator() | ForEach-Object {{ $_.Key + '=' + $_.Value + [char]0 }}))\""
+        )
+    } else {
+        format!("env -0 > \"${CAPTURE_VARIABLE}\"")
+    }
+}
+
+impl Shell {
+    /// Shell used when neither the step, its block nor the global config picks one.
+    pub fn default_for(os: &str) -> Shell {
+        if os == "windows" {
+            Shell::Profile(ShellProfile::Cmd)
+        } else {
+            Shell::Profile(ShellProfile::Sh)
+        }
+    }
+
+    /// Program of a POSIX shell able to host a session on `os`, if this is one.
+    pub fn session_program(&self, os: &str) -> Option<&'static str> {
+        match self {
+            Shell::Profile(
+                profile @ (ShellProfile::Sh | ShellProfile::Bash | ShellProfile::Zsh),
+            ) => Some(profile.program(os)),
+            _ => None,
+        }
+    }
+}
+
+impl ShellProfile {
+    /// Program of the profile on `os`, where Windows installs Python 3 as `python`.
+    fn program(self, os: &str) -> &'static str {
+        match self {
+            ShellProfile::Sh => "sh",
+            ShellProfile::Bash => "bash",
+            ShellProfile::Zsh => "zsh",
+            ShellProfile::Pwsh => "pwsh",
+            ShellProfile::Powershell => "powershell",
+            ShellProfile::Python if os == "windows" => "python",
+            ShellProfile::Python => "python3",
+            ShellProfile::Cmd => "cmd",
+        }
+    }
+
+    fn flags(self) -> &'static [&'static str] {
+        match self {
+            ShellProfile::Pwsh | ShellProfile::Powershell => {
+                &["-NoProfile", "-NonInteractive", "-Command"]
+            }
+            ShellProfile::Cmd => &["/D", "/C"],
+            _ => &["-c"],
+        }
+    }
+
+    fn capture(self) -> CaptureStrategy {
+        match self {
+            ShellProfile::Sh | ShellProfile::Bash | ShellProfile::Zsh => CaptureStrategy::Posix,
+            ShellProfile::Pwsh | ShellProfile::Powershell => CaptureStrategy::Pwsh,
+            ShellProfile::Python => CaptureStrategy::Python,
+            ShellProfile::Cmd => CaptureStrategy::None,
+        }
+    }
+}
+
+/// Installs the exit trap dumping the environment. `trap` is aliased so that an exit trap of
+/// the step runs between two dumps instead of replacing ours, zsh using its `zshexit` hook
+/// since its exit traps set from a function fire when the function returns.
+fn posix_prelude() -> String {
+    format!(
+        r#"__zbuild_dump() {{ __zbuild_status=$?; {dump}; return $__zbuild_status; }}
+if [ -n "${{ZSH_VERSION-}}" ]; then zshexit() {{ __zbuild_dump; }}; else
+trap __zbuild_dump EXIT
+__zbuild_trap() {{
+if [ "${{1-}}" = -- ]; then shift; fi
+case $#:${{1-}} in 0:* | 1:* | *:-?*) trap "$@"; return ;; esac
+__zbuild_action=$1
+shift
+for __zbuild_condition do
+case $__zbuild_condition:$__zbuild_action in
+EXIT: | EXIT:- | 0: | 0:-) trap __zbuild_dump EXIT ;;
+EXIT:* | 0:*) trap "__zbuild_dump
+$__zbuild_action
+__zbuild_dump" EXIT ;;
+*) trap -- "$__zbuild_action" "$__zbuild_condition" ;;
+esac || return
+done
+}}
+[ -z "${{BASH_VERSION-}}" ] || shopt -s expand_aliases
+alias trap=__zbuild_trap
+fi
+"#,
+        dump = env_dump_command(false)
+    )
+}
+
+/// Wraps `cmdline` so that the interpreter dumps its environment once the script is over,
+/// however it ends (trailing `;`, `&`, comments, explicit exits...).
+fn wrap_script(cmdline: &str, strategy: CaptureStrategy) -> String {
+    match strategy {
+        CaptureStrategy::Posix => format!("{}{cmdline}\n", posix_prelude()),
+        CaptureStrategy::Pwsh => format!(
+            "try {{\n{cmdline}\n}} finally {{\n[IO.File]::WriteAllText($env:{CAPTURE_VARIABLE}, -join (Get-ChildItem env: | ForEach-Object {{ $_.Name + '=' + $_.Value + [char]0 }}))\n}}\nif ((Test-Path -LiteralPath variable:\\LASTEXITCODE)) {{ exit $LASTEXITCODE }}\n"
+        ),
+        CaptureStrategy::Python => format!(
+            "import atexit as __zbuild_atexit, os as __zbuild_os\n__zbuild_target = __zbuild_os.environ[\"{CAPTURE_VARIABLE}\"]\ndef __zbuild_dump():\n    with open(__zbuild_target, \"w\", encoding=\"utf-8\") as f:\n        f.write(\"\".join(k + \"=\" + v + \"\\0\" for k, v in __zbuild_os.environ.items()))\n__zbuild_atexit.register(__zbuild_dump)\n{cmdline}\n"
+        ),
+        CaptureStrategy::None => cmdline.to_string(),
+    }
+}
+
+/// Builds the process running `cmdline` with `shell` on `os`, dumping its final environment
+/// into `capture`. On windows `cmd`, a plain `exit` loses the variables the command set.
+pub(crate) fn shell_command(
+    cmdline: &str,
+    shell: &Shell,
+    os: &str,
+    capture: &mut Capture,
+) -> io::Result<Command> {
+    let mut cmd = match shell {
+        Shell::Profile(ShellProfile::Cmd) => {
+            let mut c = cmd_command(cmdline, capture)?;
+            c.env("ANSICON", "1");
+            c
+        }
+        Shell::Profile(profile) => {
+            let mut c = Command::new(profile.program(os));
+            c.args(profile.flags())
+                .arg(wrap_script(cmdline, profile.capture()));
+            c
+        }
+        Shell::Custom(custom) => {
+            let Some((program, args)) = custom.argv.split_first() else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "custom shell needs at least a program in `argv`",
+                ));
+            };
+            let script = wrap_script(cmdline, custom.capture);
+            let mut c = Command::new(program);
+            if args.iter().any(|arg| arg.contains(SCRIPT_PLACEHOLDER)) {
+                c.args(
+                    args.iter()
+                        .map(|arg| arg.replace(SCRIPT_PLACEHOLDER, &script)),
+                );
+            } else {
+                c.args(args).arg(script);
+            }
+            c
+        }
+    };
+    cmd.env(CAPTURE_VARIABLE, &capture.dump);
+    cmd.env("TERM", "xterm-256color");
+    own_process_group(&mut cmd);
+    Ok(cmd)
+}
+
+/// Builds a shell reading its script from stdin, used by sessions.
+pub(crate) fn session_command(shell: &str, capture: &Capture) -> Command {
+    let mut cmd = Command::new(shell);
+    cmd.env(CAPTURE_VARIABLE, &capture.dump);
+    cmd.env("TERM", "xterm-256color");
+    own_process_group(&mut cmd);
+    cmd
+}
+
+/// Looks `name` up in the directories of `PATH` like a shell would.
+pub(crate) fn find_executable(name: &str) -> Option<PathBuf> {
+    let extensions: &[&str] = if cfg!(windows) {
+        &["", ".exe", ".cmd", ".bat"]
+    } else {
+        &[""]
+    };
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).find_map(|dir| {
+        extensions
+            .iter()
+            .map(|extension| dir.join(format!("{name}{extension}")))
+            .find(|candidate| is_executable(candidate))
+    })
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Quotes `value` as a single word for POSIX shells.
+pub(crate) fn quote_posix(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// `cmd` has no exit trap and a plain `exit` ends the whole interpreter. The command runs as
+/// a subroutine of a batch file dumping the environment once it returns, in a child `cmd` so
+/// that the outer batch still dumps its own environment when a plain `exit` skipped that.
+fn cmd_command(cmdline: &str, capture: &mut Capture) -> io::Result<Command> {
+    let step = unique_temp_file("cmd")?;
+    capture.scripts.push(step.clone());
+    fs::write(&step, cmd_step_script(cmdline))?;
+    let script = unique_temp_file("cmd")?;
+    capture.scripts.push(script.clone());
+    fs::write(&script, cmd_outer_script(&step))?;
+    let mut c = Command::new(ShellProfile::Cmd.program("windows"));
+    c.args(ShellProfile::Cmd.flags()).arg(&script);
+    Ok(c)
+}
+
+fn cmd_step_script(cmdline: &str) -> String {
+    format!(
+        "@echo off\r\ncall :zbuild_step\r\nset ZBUILD_EXIT_CODE=%ERRORLEVEL%\r\n{}\r\nexit /b %ZBUILD_EXIT_CODE%\r\n:zbuild_step\r\n{cmdline}\r\n",
+        env_dump_command(true)
+    )
+}
+
+/// Runs the step script in a child `cmd`, dumping the environment unless the child did.
+fn cmd_outer_script(step: &Path) -> String {
+    format!(
+        "@echo off\r\ncmd /D /C \"{}\"\r\nset ZBUILD_EXIT_CODE=%ERRORLEVEL%\r\nfor %%F in (\"%{CAPTURE_VARIABLE}%\") do if %%~zF==0 {}\r\nexit /b %ZBUILD_EXIT_CODE%\r\n",
+        step.display(),
+        env_dump_command(true)
+    )
+}
+
+/// Copies `reader` line by line to our stdout (or stderr), prefixing every line with `prefix`.
+pub(crate) fn forward_prefixed(reader: impl Read, prefix: &str, to_stderr: bool) {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end_matches(['\n', '\r']);
+        if to_stderr {
+            eprintln!("[{prefix}] {text}");
+        } else {
+            println!("[{prefix}] {text}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn python_program_follows_the_target_os() {
+        assert_eq!(ShellProfile::Python.program("windows"), "python");
+        assert_eq!(ShellProfile::Python.program("linux"), "python3");
+        assert_eq!(ShellProfile::Python.program("macos"), "python3");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_traps_of_the_step_keep_the_dump() {
+        let script = "cleanup() { export FROM_TRAP=$?; }\ntrap cleanup EXIT\ntrap 'echo int' INT\nexport FOO=bar\nexit 3";
+        for profile in [ShellProfile::Sh, ShellProfile::Bash] {
+            let mut capture = Capture::new().unwrap();
+            let status = shell_command(script, &Shell::Profile(profile), "linux", &mut capture)
+                .unwrap()
+                .status()
+                .unwrap();
+            assert_eq!(status.code(), Some(3));
+            let dump = capture.read().unwrap();
+            assert!(dump.split('\0').any(|entry| entry == "FOO=bar"));
+            assert!(dump.split('\0').any(|entry| entry == "FROM_TRAP=3"));
+        }
+    }
+
+    #[test]
+    fn cmd_dumps_after_the_child_running_the_step() {
+        let step = cmd_step_script("set FOO=bar\r\nexit 1");
+        assert!(step.find(":zbuild_step").unwrap() < step.find("set FOO=bar").unwrap());
+
+        let outer = cmd_outer_script(Path::new("step.cmd"));
+        let child = outer.find("cmd /D /C \"step.cmd\"").unwrap();
+        let dump = outer.find(&env_dump_command(true)).unwrap();
+        assert!(child < dump);
+        assert!(outer.ends_with("exit /b %ZBUILD_EXIT_CODE%\r\n"));
+    }
+}